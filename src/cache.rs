@@ -0,0 +1,553 @@
+//! Managing the cached careful sysroots (`cargo careful sysroot ...`).
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use rustc_version::VersionMeta;
+
+use crate::rustc_version_info;
+use crate::setup::{self, SysrootId, MANIFEST_FILE_NAME};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A sysroot in the cache, as described by its manifest.
+pub struct CachedSysroot {
+    /// The name of the sysroot directory, which identifies the sysroot.
+    pub id: String,
+    pub dir: PathBuf,
+    manifest: serde_json::Value,
+}
+
+impl CachedSysroot {
+    fn load(dir: PathBuf) -> Result<Self> {
+        let id = dir.file_name().unwrap().to_string_lossy().into_owned();
+        let manifest = fs::read(dir.join(MANIFEST_FILE_NAME)).with_context(|| {
+            format!(
+                "sysroot `{id}` is incomplete (its build did not finish); \
+                 `cargo careful sysroot gc` removes it"
+            )
+        })?;
+        let manifest = serde_json::from_slice(&manifest)
+            .with_context(|| format!("sysroot `{id}` has an invalid manifest"))?;
+        Ok(CachedSysroot { id, dir, manifest })
+    }
+
+    fn get_str(&self, key: &str) -> Option<&str> {
+        self.manifest.get(key)?.as_str()
+    }
+
+    fn get_list(&self, key: &str) -> Option<Vec<&str>> {
+        self.manifest
+            .get(key)?
+            .as_array()?
+            .iter()
+            .map(serde_json::Value::as_str)
+            .collect()
+    }
+
+    pub fn rustc(&self) -> &str {
+        self.get_str("rustc").unwrap_or("<unknown>")
+    }
+
+    pub fn rustc_commit(&self) -> &str {
+        self.get_str("rustc-commit").unwrap_or("<unknown>")
+    }
+
+    pub fn target(&self) -> &str {
+        self.get_str("target").unwrap_or("<unknown>")
+    }
+
+    pub fn sanitizer(&self) -> Option<&str> {
+        self.get_str("sanitizer")
+    }
+
+    /// The panic strategy, `unwind` or `abort`.
+    pub fn panic(&self) -> &str {
+        self.get_str("panic").unwrap_or("unwind")
+    }
+
+    pub fn rustflags(&self) -> Vec<&str> {
+        self.get_list("rustflags").unwrap_or_default()
+    }
+
+    /// The `std` features, or `None` for a no-std sysroot.
+    pub fn std_features(&self) -> Option<Vec<&str>> {
+        self.get_list("std-features")
+    }
+
+    /// The patches and overlays that were applied to the standard library sources.
+    pub fn std_patches(&self) -> Option<Vec<&str>> {
+        self.get_list("std-patches")
+    }
+
+    /// Whether this sysroot was imported from an archive rather than built locally.
+    pub fn imported(&self) -> bool {
+        self.manifest.get("imported") == Some(&serde_json::Value::Bool(true))
+    }
+
+    pub fn last_used(&self) -> Option<SystemTime> {
+        let secs = self.manifest.get("last-used")?.as_u64()?;
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+}
+
+/// Returns all sysroots in the cache, sorted by when they were last used (most recent first).
+/// Directories that are not a valid sysroot are reported but skipped.
+pub fn cached_sysroots() -> Result<Vec<CachedSysroot>> {
+    let dir = setup::sysroots_dir();
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut sysroots = Vec::new();
+    for entry in fs::read_dir(&dir).context("failed to read sysroot cache directory")? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        match CachedSysroot::load(entry.path()) {
+            Ok(sysroot) => sysroots.push(sysroot),
+            Err(e) => eprintln!("warn: {e:#}"),
+        }
+    }
+    sysroots.sort_by_key(|s| std::cmp::Reverse(s.last_used()));
+    Ok(sysroots)
+}
+
+/// Finds the cached sysroot with the given id, or a unique prefix of it.
+fn find_sysroot(id: &str) -> Result<CachedSysroot> {
+    let mut matching: Vec<_> = cached_sysroots()?
+        .into_iter()
+        .filter(|s| s.id.starts_with(id))
+        .collect();
+    match matching.len() {
+        0 => bail!("no cached sysroot with id `{id}`"),
+        1 => Ok(matching.pop().unwrap()),
+        _ => bail!("`{id}` is ambiguous; it matches multiple cached sysroots"),
+    }
+}
+
+/// Computes the total size of all files in a directory.
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if file_type.is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+    Ok(size)
+}
+
+fn format_size(bytes: u64) -> String {
+    const MIB: f64 = 1024.0 * 1024.0;
+    format!("{:.1} MiB", bytes as f64 / MIB)
+}
+
+fn format_age(time: Option<SystemTime>) -> String {
+    let Some(age) = time.and_then(|t| t.elapsed().ok()) else {
+        return "unknown".to_owned();
+    };
+    match age.as_secs() / SECS_PER_DAY {
+        0 => "today".to_owned(),
+        1 => "1 day ago".to_owned(),
+        days => format!("{days} days ago"),
+    }
+}
+
+fn list() -> Result<()> {
+    let sysroots = cached_sysroots()?;
+    if sysroots.is_empty() {
+        eprintln!("No careful sysroots are cached.");
+        return Ok(());
+    }
+    let mut total = 0;
+    for sysroot in &sysroots {
+        let size = dir_size(&sysroot.dir)?;
+        total += size;
+        println!(
+            "{}  {} (sanitizer: {})",
+            sysroot.id,
+            sysroot.target(),
+            sysroot.sanitizer().unwrap_or("none"),
+        );
+        println!("    toolchain: {}", sysroot.rustc());
+        println!("    flags: {}", sysroot.rustflags().join(" "));
+        println!(
+            "    size: {}, last used: {}",
+            format_size(size),
+            format_age(sysroot.last_used())
+        );
+    }
+    println!(
+        "{} sysroot(s), {} in total, cached in `{}`",
+        sysroots.len(),
+        format_size(total),
+        setup::sysroots_dir().display()
+    );
+    Ok(())
+}
+
+fn info(id: &str) -> Result<()> {
+    let sysroot = find_sysroot(id)?;
+    println!("id:           {}", sysroot.id);
+    println!("path:         {}", sysroot.dir.display());
+    println!("toolchain:    {}", sysroot.rustc());
+    println!("rustc commit: {}", sysroot.rustc_commit());
+    println!("target:       {}", sysroot.target());
+    println!("sanitizer:    {}", sysroot.sanitizer().unwrap_or("none"));
+    println!("panic:        {}", sysroot.panic());
+    println!("flags:        {}", sysroot.rustflags().join(" "));
+    match sysroot.std_features() {
+        Some(features) => println!("std features: {}", features.join(" ")),
+        None => println!("std features: no std"),
+    }
+    if let Some(patches) = sysroot.std_patches().filter(|p| !p.is_empty()) {
+        println!("std patches:  {}", patches.join(" "));
+    }
+    println!("size:         {}", format_size(dir_size(&sysroot.dir)?));
+    println!("last used:    {}", format_age(sysroot.last_used()));
+    println!(
+        "origin:       {}",
+        if sysroot.imported() {
+            "imported"
+        } else {
+            "built locally"
+        }
+    );
+    Ok(())
+}
+
+fn remove(sysroot: &CachedSysroot) -> Result<()> {
+    let lock = setup::lock_sysroot(&sysroot.dir)?;
+    fs::remove_dir_all(&sysroot.dir)
+        .with_context(|| format!("failed to remove sysroot `{}`", sysroot.id))?;
+    setup::remove_lock_file(&sysroot.dir, lock)?;
+    eprintln!(
+        "Removed sysroot {} ({}, {}).",
+        sysroot.id,
+        sysroot.target(),
+        sysroot.rustc()
+    );
+    Ok(())
+}
+
+/// Removes the sysroot dirs without a manifest, which are left behind by failed or interrupted
+/// builds, and lock files without a sysroot dir. Returns how many sysroot dirs were removed.
+fn remove_incomplete_sysroots() -> Result<usize> {
+    let dir = setup::sysroots_dir();
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let mut candidates = BTreeSet::new();
+    for entry in fs::read_dir(&dir).context("failed to read sysroot cache directory")? {
+        let path = entry?.path();
+        if path.extension() == Some(OsStr::new("lock")) {
+            candidates.insert(path.with_extension(""));
+        } else if path.is_dir() {
+            candidates.insert(path);
+        }
+    }
+    let mut removed = 0;
+    for sysroot_dir in candidates {
+        if sysroot_dir.join(MANIFEST_FILE_NAME).exists() {
+            continue;
+        }
+        let lock = setup::lock_sysroot(&sysroot_dir)?;
+        // The sysroot might have been built while we waited for the lock.
+        if sysroot_dir.join(MANIFEST_FILE_NAME).exists() {
+            continue;
+        }
+        if sysroot_dir.exists() {
+            fs::remove_dir_all(&sysroot_dir)
+                .with_context(|| format!("failed to remove `{}`", sysroot_dir.display()))?;
+            eprintln!(
+                "Removed incomplete sysroot {}.",
+                sysroot_dir.file_name().unwrap().to_string_lossy()
+            );
+            removed += 1;
+        }
+        setup::remove_lock_file(&sysroot_dir, lock)?;
+    }
+    Ok(removed)
+}
+
+/// Removes sysroots that were created by older versions of cargo-careful, which stored them
+/// directly in the cache directory.
+fn remove_legacy_sysroots() -> Result<()> {
+    let cache_dir = setup::cache_dir();
+    if !cache_dir.exists() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(&cache_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type()?.is_dir() || path == setup::sysroots_dir() {
+            continue;
+        }
+        if entry.file_name() == "lib" || path.join("lib").join("rustlib").exists() {
+            fs::remove_dir_all(&path)
+                .with_context(|| format!("failed to remove `{}`", path.display()))?;
+            eprintln!("Removed legacy sysroot `{}`.", path.display());
+        }
+    }
+    Ok(())
+}
+
+fn clean(args: &[String]) -> Result<()> {
+    if args.is_empty() {
+        bail!("`cargo careful sysroot clean` expects sysroot ids, or `--all`");
+    }
+    if args.iter().any(|a| a == "--all") {
+        for sysroot in cached_sysroots()? {
+            remove(&sysroot)?;
+        }
+        remove_incomplete_sysroots()?;
+        return remove_legacy_sysroots();
+    }
+    // Look up all sysroots before deleting any, so that a typo does not leave us half-done.
+    let sysroots = args
+        .iter()
+        .map(|id| find_sysroot(id))
+        .collect::<Result<Vec<_>>>()?;
+    for sysroot in &sysroots {
+        remove(sysroot)?;
+    }
+    Ok(())
+}
+
+/// Extracts the toolchain name from a line of `rustup toolchain list`, which can be followed by
+/// annotations like `(default)`.
+fn toolchain_name(line: &str) -> Option<&str> {
+    line.split_whitespace().next()
+}
+
+/// Determines the commit hashes of all toolchains installed via rustup, plus the current one (see
+/// [`setup::rustc_id`]). Returns `None` if rustup is not available or we cannot determine the
+/// version of some toolchain, since then we cannot tell which sysroots are still needed.
+fn installed_rustc_commits(current: &VersionMeta) -> Option<HashSet<String>> {
+    let output = Command::new("rustup")
+        .args(["toolchain", "list"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())?;
+    let mut commits = HashSet::new();
+    for name in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(toolchain_name)
+    {
+        let mut rustc = Command::new("rustup");
+        rustc.args(["run", name, "rustc"]);
+        let version = VersionMeta::for_command(rustc).ok()?;
+        commits.insert(setup::rustc_id(&version));
+    }
+    if commits.is_empty() {
+        return None;
+    }
+    commits.insert(setup::rustc_id(current));
+    Some(commits)
+}
+
+fn gc(args: &[String]) -> Result<()> {
+    let mut max_age_days = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let days = match arg.strip_prefix("--days") {
+            Some("") => args.next().map(String::as_str),
+            Some(value) => value.strip_prefix('='),
+            None => bail!("unsupported argument `{arg}` for `cargo careful sysroot gc`"),
+        };
+        let days = days.context("`--days` expects a number")?;
+        max_age_days = Some(
+            days.parse::<u64>()
+                .with_context(|| format!("`--days` expects a number, not `{days}`"))?,
+        );
+    }
+
+    let installed = installed_rustc_commits(&rustc_version_info());
+    if installed.is_none() {
+        eprintln!(
+            "warn: could not determine the installed rustup toolchains; not checking for \
+             uninstalled toolchains"
+        );
+    }
+    let mut removed = 0;
+    for sysroot in cached_sysroots()? {
+        let uninstalled = installed
+            .as_ref()
+            .is_some_and(|commits| !commits.contains(sysroot.rustc_commit()));
+        let unused = max_age_days.is_some_and(|days| {
+            sysroot
+                .last_used()
+                .and_then(|t| t.elapsed().ok())
+                .is_none_or(|age| age.as_secs() > days * SECS_PER_DAY)
+        });
+        if uninstalled || unused {
+            remove(&sysroot)?;
+            removed += 1;
+        }
+    }
+    removed += remove_incomplete_sysroots()?;
+    remove_legacy_sysroots()?;
+    eprintln!("Removed {removed} sysroot(s).");
+    Ok(())
+}
+
+/// Runs `tar` with the given arguments.
+fn tar(args: &[&OsStr]) -> Result<()> {
+    let mut cmd = Command::new("tar");
+    cmd.args(args);
+    let status = cmd
+        .status()
+        .with_context(|| format!("failed to run {cmd:?}; is `tar` installed?"))?;
+    if !status.success() {
+        bail!("{cmd:?} failed");
+    }
+    Ok(())
+}
+
+/// Packs a cached sysroot, including its manifest, into an archive. Without an id, the most
+/// recently used sysroot is exported.
+fn export(args: &[String]) -> Result<()> {
+    let (file, sysroot) = match args {
+        [file] => {
+            let sysroot = cached_sysroots()?
+                .into_iter()
+                .next()
+                .context("no careful sysroots are cached; run `cargo careful setup` first")?;
+            (file, sysroot)
+        }
+        [file, id] => (file, find_sysroot(id)?),
+        _ => {
+            bail!("`cargo careful sysroot export` expects a file name and optionally a sysroot id")
+        }
+    };
+    // `-a` picks the compression based on the file extension.
+    tar(&[
+        "-caf".as_ref(),
+        file.as_ref(),
+        "-C".as_ref(),
+        sysroot.dir.as_os_str(),
+        ".".as_ref(),
+    ])?;
+    eprintln!(
+        "Exported sysroot {} ({}, {}) to `{file}`.",
+        sysroot.id,
+        sysroot.target(),
+        sysroot.rustc()
+    );
+    Ok(())
+}
+
+/// Unpacks an archive created by `export` into the cache, after checking that it fits the current
+/// toolchain.
+fn import(args: &[String]) -> Result<()> {
+    let [file] = args else {
+        bail!("`cargo careful sysroot import` expects a file name");
+    };
+    let file = Path::new(file)
+        .canonicalize()
+        .with_context(|| format!("archive `{file}` not found"))?;
+    // Unpack into the cache directory, so that we can move the sysroot to its final place. We stay
+    // out of the sysroots directory, where `gc` would consider the unfinished sysroot incomplete.
+    let imports_dir = setup::cache_dir().join("imports");
+    fs::create_dir_all(&imports_dir).context("failed to create import staging directory")?;
+    fs::create_dir_all(setup::sysroots_dir())
+        .context("failed to create sysroot cache directory")?;
+    let staging_dir =
+        tempfile::TempDir::new_in(&imports_dir).context("failed to create staging dir")?;
+    tar(&[
+        "-xf".as_ref(),
+        file.as_os_str(),
+        "-C".as_ref(),
+        staging_dir.path().as_os_str(),
+    ])?;
+
+    let sysroot = CachedSysroot::load(staging_dir.path().to_owned())
+        .context("archive does not contain a careful sysroot")?;
+    let id = SysrootId::from_manifest(&sysroot.manifest)?;
+    let rustc_version = rustc_version_info();
+    if id.rustc_commit != setup::rustc_id(&rustc_version) {
+        bail!(
+            "the archive contains a sysroot for `{}`, but the current toolchain is `{}`",
+            sysroot.rustc(),
+            rustc_version.short_version_string
+        );
+    }
+    fs::write(
+        staging_dir.path().join(MANIFEST_FILE_NAME),
+        id.manifest(
+            &rustc_version,
+            /* imported */ true,
+            sysroot
+                .manifest
+                .get("crates")
+                .and_then(serde_json::Value::as_u64),
+        )
+        .to_string(),
+    )
+    .context("failed to write sysroot manifest")?;
+
+    // Replace any existing sysroot for the same configuration.
+    let dir = id.dir();
+    let _lock = setup::lock_sysroot(&dir)?;
+    if dir.exists() {
+        fs::remove_dir_all(&dir).context("failed to remove existing sysroot")?;
+    }
+    fs::rename(staging_dir.keep(), &dir).context("failed to move sysroot into the cache")?;
+    eprintln!(
+        "Imported sysroot {} ({}, {}).",
+        dir.file_name().unwrap().to_string_lossy(),
+        id.target,
+        sysroot.rustc()
+    );
+    Ok(())
+}
+
+/// Implements `cargo careful sysroot <subcommand>`.
+pub fn cargo_careful_sysroot(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let subcommand = args.next();
+    let args: Vec<String> = args.collect();
+    match subcommand.as_deref() {
+        Some("list") => list(),
+        Some("info") => match &args[..] {
+            [id] => info(id),
+            _ => bail!("`cargo careful sysroot info` expects exactly one sysroot id"),
+        },
+        Some("clean") => clean(&args),
+        Some("gc") => gc(&args),
+        Some("export") => export(&args),
+        Some("import") => import(&args),
+        _ => bail!(
+            "`cargo careful sysroot` supports the following subcommands: `list`, `info`, `clean`, `gc`, `export`, and `import`."
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toolchain_names() {
+        for line in [
+            "nightly-x86_64-unknown-linux-gnu",
+            "nightly-x86_64-unknown-linux-gnu (default)",
+            "nightly-x86_64-unknown-linux-gnu (active, default)",
+            "nightly-x86_64-unknown-linux-gnu\t(default)",
+            "  nightly-x86_64-unknown-linux-gnu  ",
+        ] {
+            assert_eq!(
+                toolchain_name(line),
+                Some("nightly-x86_64-unknown-linux-gnu")
+            );
+        }
+        assert_eq!(toolchain_name(""), None);
+    }
+}
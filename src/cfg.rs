@@ -0,0 +1,282 @@
+//! Evaluation of `cfg(...)` expressions, as used in `target.'cfg(...)'` cargo config tables.
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use anyhow::{bail, Context, Result};
+
+/// A single `cfg` as printed by `rustc --print cfg`: either `name` or `name="value"`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cfg {
+    Name(String),
+    KeyPair(String, String),
+}
+
+impl Cfg {
+    /// Parses one line of `rustc --print cfg` output.
+    pub fn parse(line: &str) -> Result<Cfg> {
+        let line = line.trim();
+        Ok(match line.split_once('=') {
+            None => Cfg::Name(line.to_owned()),
+            Some((key, value)) => {
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .with_context(|| format!("invalid cfg value in `{line}`"))?;
+                Cfg::KeyPair(key.to_owned(), value.to_owned())
+            }
+        })
+    }
+}
+
+/// Parses the output of `rustc --print cfg`.
+pub fn parse_cfgs(output: &str) -> Result<Vec<Cfg>> {
+    output
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(Cfg::parse)
+        .collect()
+}
+
+#[derive(Debug)]
+enum CfgExpr {
+    Not(Box<CfgExpr>),
+    All(Vec<CfgExpr>),
+    Any(Vec<CfgExpr>),
+    Value(Cfg),
+}
+
+impl CfgExpr {
+    fn matches(&self, cfgs: &[Cfg]) -> bool {
+        match self {
+            CfgExpr::Not(e) => !e.matches(cfgs),
+            CfgExpr::All(es) => es.iter().all(|e| e.matches(cfgs)),
+            CfgExpr::Any(es) => es.iter().any(|e| e.matches(cfgs)),
+            CfgExpr::Value(cfg) => cfgs.contains(cfg),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token<'a> {
+    LeftParen,
+    RightParen,
+    Comma,
+    Equals,
+    Ident(&'a str),
+    String(&'a str),
+}
+
+struct Tokenizer<'a> {
+    s: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<Token<'a>>;
+
+    fn next(&mut self) -> Option<Result<Token<'a>>> {
+        loop {
+            let (start, c) = self.chars.next()?;
+            return Some(Ok(match c {
+                c if c.is_whitespace() => continue,
+                '(' => Token::LeftParen,
+                ')' => Token::RightParen,
+                ',' => Token::Comma,
+                '=' => Token::Equals,
+                '"' => {
+                    let Some((end, _)) = self.chars.find(|&(_, c)| c == '"') else {
+                        return Some(Err(anyhow::anyhow!("unterminated string in cfg")));
+                    };
+                    Token::String(&self.s[start + 1..end])
+                }
+                c if c == '_' || c.is_alphabetic() => {
+                    let mut end = start + c.len_utf8();
+                    while let Some(&(i, c)) = self.chars.peek() {
+                        if c == '_' || c.is_alphanumeric() {
+                            end = i + c.len_utf8();
+                            self.chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    Token::Ident(&self.s[start..end])
+                }
+                c => return Some(Err(anyhow::anyhow!("unexpected character `{c}` in cfg"))),
+            }));
+        }
+    }
+}
+
+struct Parser<'a> {
+    tokens: Peekable<Tokenizer<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(s: &'a str) -> Self {
+        Parser {
+            tokens: Tokenizer {
+                s,
+                chars: s.char_indices().peekable(),
+            }
+            .peekable(),
+        }
+    }
+
+    fn next(&mut self) -> Result<Option<Token<'a>>> {
+        self.tokens.next().transpose()
+    }
+
+    fn eat(&mut self, token: &Token<'_>) -> Result<bool> {
+        match self.tokens.peek() {
+            Some(Ok(t)) if t == token => {
+                self.tokens.next();
+                Ok(true)
+            }
+            Some(Err(_)) => Err(self.tokens.next().unwrap().unwrap_err()),
+            _ => Ok(false),
+        }
+    }
+
+    fn expect(&mut self, token: &Token<'_>) -> Result<()> {
+        if !self.eat(token)? {
+            bail!("expected {token:?} in cfg");
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<CfgExpr> {
+        let Some(Token::Ident(ident)) = self.next()? else {
+            bail!("expected identifier in cfg");
+        };
+        match ident {
+            "all" | "any" => {
+                self.expect(&Token::LeftParen)?;
+                let mut exprs = Vec::new();
+                while !self.eat(&Token::RightParen)? {
+                    exprs.push(self.expr()?);
+                    if !self.eat(&Token::Comma)? {
+                        self.expect(&Token::RightParen)?;
+                        break;
+                    }
+                }
+                Ok(if ident == "all" {
+                    CfgExpr::All(exprs)
+                } else {
+                    CfgExpr::Any(exprs)
+                })
+            }
+            "not" => {
+                self.expect(&Token::LeftParen)?;
+                let e = self.expr()?;
+                self.expect(&Token::RightParen)?;
+                Ok(CfgExpr::Not(Box::new(e)))
+            }
+            _ => {
+                if self.eat(&Token::Equals)? {
+                    let Some(Token::String(value)) = self.next()? else {
+                        bail!("expected string after `{ident} =` in cfg");
+                    };
+                    Ok(CfgExpr::Value(Cfg::KeyPair(
+                        ident.to_owned(),
+                        value.to_owned(),
+                    )))
+                } else {
+                    Ok(CfgExpr::Value(Cfg::Name(ident.to_owned())))
+                }
+            }
+        }
+    }
+}
+
+/// Determines whether a `target` table key like `cfg(unix)` matches the given cfgs.
+pub fn key_matches(key: &str, cfgs: &[Cfg]) -> Result<bool> {
+    (|| {
+        let Some(inner) = key.strip_prefix("cfg(").and_then(|k| k.strip_suffix(')')) else {
+            bail!("not of the form `cfg(...)`");
+        };
+        let mut parser = Parser::new(inner);
+        let expr = parser.expr()?;
+        if parser.next()?.is_some() {
+            bail!("unexpected trailing tokens in cfg");
+        }
+        Ok(expr.matches(cfgs))
+    })()
+    .with_context(|| format!("failed to parse `{key}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfgs() -> Vec<Cfg> {
+        parse_cfgs("unix\ntarget_os=\"linux\"\ntarget_pointer_width=\"64\"\n").unwrap()
+    }
+
+    fn matches(key: &str) -> bool {
+        key_matches(key, &cfgs()).unwrap()
+    }
+
+    #[test]
+    fn parse_cfgs_output() {
+        assert_eq!(
+            cfgs(),
+            vec![
+                Cfg::Name("unix".into()),
+                Cfg::KeyPair("target_os".into(), "linux".into()),
+                Cfg::KeyPair("target_pointer_width".into(), "64".into()),
+            ]
+        );
+        assert!(Cfg::parse("target_os=linux").is_err());
+    }
+
+    #[test]
+    fn names_and_values() {
+        assert!(matches("cfg(unix)"));
+        assert!(!matches("cfg(windows)"));
+        assert!(matches("cfg(target_os = \"linux\")"));
+        assert!(matches("cfg(target_os=\"linux\")"));
+        assert!(!matches("cfg(target_os = \"macos\")"));
+        // A name does not match a key/value pair with that key.
+        assert!(!matches("cfg(target_os)"));
+    }
+
+    #[test]
+    fn combinators() {
+        assert!(matches("cfg(all())"));
+        assert!(!matches("cfg(any())"));
+        assert!(matches("cfg(all(unix, target_pointer_width = \"64\"))"));
+        assert!(!matches("cfg(all(unix, windows))"));
+        assert!(matches("cfg(any(windows, unix))"));
+        assert!(matches("cfg(any(windows, unix,))"));
+        assert!(matches("cfg(all(unix,))"));
+        assert!(matches("cfg(not(windows))"));
+        assert!(!matches("cfg(not(unix))"));
+        assert!(matches(
+            "cfg(all(not(windows), any(target_os = \"linux\", target_os = \"macos\")))"
+        ));
+    }
+
+    #[test]
+    fn invalid() {
+        let cfgs = cfgs();
+        for key in [
+            "unix",
+            "cfg(unix",
+            "cfg(unix windows)",
+            "cfg(unix))",
+            "cfg(all(unix)",
+            "cfg(not(unix, windows))",
+            "cfg(not())",
+            "cfg(target_os = linux)",
+            "cfg(target_os = \"linux)",
+            "cfg(any(,))",
+            "cfg(unix = )",
+            "cfg(#)",
+        ] {
+            assert!(
+                key_matches(key, &cfgs).is_err(),
+                "`{key}` should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! Querying the cargo configuration, and determining flags the same way cargo does.
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use crate::cfg::{self, Cfg};
+use crate::target;
+use crate::util::*;
+use crate::{cargo, rustc};
+
+// Computes the extra flags that need to be passed to cargo to make it behave like the current
+// cargo invocation.
+fn cargo_extra_flags() -> Vec<String> {
+    let mut flags = Vec::new();
+    // `-Zunstable-options` is required by `--config`.
+    flags.push("-Zunstable-options".to_string());
+
+    // Forward `--config` flags.
+    let config_flag = "--config";
+    for arg in get_arg_flag_values(config_flag) {
+        flags.push(config_flag.to_string());
+        flags.push(arg);
+    }
+
+    // Forward `--manifest-path`.
+    let manifest_flag = "--manifest-path";
+    if let Some(manifest) = get_arg_flag_value(manifest_flag) {
+        flags.push(manifest_flag.to_string());
+        flags.push(manifest);
+    }
+
+    // Forwarding `--target-dir` would make sense, but `cargo metadata` does not support that flag.
+
+    flags
+}
+
+/// Asks `cargo config` for the given key. Returns `None` if the key is not set.
+pub fn cargo_config_get(key: &str) -> Result<Option<serde_json::Value>> {
+    let mut cmd = cargo();
+    cmd.args(["config", "get", key, "--format=json-value"]);
+    cmd.args(cargo_extra_flags());
+    let output = cmd.output().context("failed to run `cargo config get`")?;
+    if !output.status.success() {
+        // This can fail if the variable is not set.
+        return Ok(None);
+    }
+    let value = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("`cargo config get {key}` returned invalid JSON"))?;
+    Ok(Some(value))
+}
+
+/// Interprets a config value that can be given either as a space-separated string or as an array
+/// of strings (like `build.rustflags`).
+fn string_list(key: &str, value: &serde_json::Value) -> Result<Vec<String>> {
+    match value {
+        serde_json::Value::String(s) => Ok(s.split_whitespace().map(str::to_string).collect()),
+        serde_json::Value::Array(arr) => arr
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map(str::to_string)
+                    .with_context(|| format!("`{key}` must be an array of strings"))
+            })
+            .collect(),
+        _ => bail!("`{key}` must be a string or an array of strings"),
+    }
+}
+
+/// Like `cargo_config_get`, but for a key holding a list of strings.
+fn cargo_config_get_list(key: &str) -> Result<Option<Vec<String>>> {
+    cargo_config_get(key)?
+        .map(|v| string_list(key, &v))
+        .transpose()
+}
+
+/// The two kinds of extra flags that cargo passes to the compiler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Flags {
+    Rustc,
+    Rustdoc,
+}
+
+impl Flags {
+    /// The name of the environment variable (without the `CARGO_ENCODED_` prefix) and of the
+    /// config key (in lowercase).
+    fn name(self) -> &'static str {
+        match self {
+            Flags::Rustc => "RUSTFLAGS",
+            Flags::Rustdoc => "RUSTDOCFLAGS",
+        }
+    }
+
+    fn key(self) -> String {
+        self.name().to_lowercase()
+    }
+
+    /// Returns the flags set via environment variables, if any.
+    fn env_flags(self) -> Option<Vec<String>> {
+        // Highest precedence: the encoded env var.
+        if let Ok(flags) = env::var(format!("CARGO_ENCODED_{}", self.name())) {
+            return Some(if flags.is_empty() {
+                vec![]
+            } else {
+                flags.split('\x1f').map(Into::into).collect()
+            });
+        }
+
+        // Next: the old var.
+        if let Ok(a) = env::var(self.name()) {
+            // This code is taken from `RUSTFLAGS` handling in cargo.
+            return Some(
+                a.split(' ')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            );
+        }
+
+        None
+    }
+}
+
+/// Returns the `cfg`s that are set for the given target.
+fn target_cfgs(target: &str) -> Result<Vec<Cfg>> {
+    // Like cargo, we query them with the rustflags we would use if there were no `cfg` tables.
+    let rustflags = match Flags::Rustc.env_flags() {
+        Some(flags) => flags,
+        None => match cargo_config_get_list(&format!(
+            "target.{}.rustflags",
+            target::short_name(target)
+        ))? {
+            Some(flags) => flags,
+            None => cargo_config_get_list("build.rustflags")?.unwrap_or_default(),
+        },
+    };
+
+    let mut cmd = rustc();
+    cmd.args(["--print", "cfg", "--target", target]);
+    if target::is_spec_file(target) {
+        // Custom targets are unstable.
+        cmd.arg("-Zunstable-options");
+    }
+    cmd.args(rustflags);
+    let output = cmd.output().context("`rustc --print cfg` failed to run")?;
+    if !output.status.success() {
+        bail!(
+            "`rustc --print cfg` failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    let output =
+        String::from_utf8(output.stdout).context("`rustc --print cfg` returned invalid UTF-8")?;
+    cfg::parse_cfgs(&output)
+}
+
+/// Determines the flags of the given kind for the given target, following the same rules as
+/// cargo. The first of these sources that is set is used (shown here for rustflags):
+/// - `CARGO_ENCODED_RUSTFLAGS`
+/// - `RUSTFLAGS`
+/// - all matching `target.<triple>.rustflags` and `target.<cfg>.rustflags` entries, joined
+/// - `build.rustflags` (which is also where `CARGO_BUILD_RUSTFLAGS` ends up)
+fn get_flags(kind: Flags, target: &str) -> Result<Vec<String>> {
+    if let Some(flags) = kind.env_flags() {
+        return Ok(flags);
+    }
+
+    // As fallback, ask `cargo config`.
+    let key = kind.key();
+    let mut flags = cargo_config_get_list(&format!("target.{}.{key}", target::short_name(target)))?
+        .unwrap_or_default();
+    if let Some(serde_json::Value::Object(tables)) = cargo_config_get("target")? {
+        let mut cfgs = None;
+        for (cfg_key, table) in &tables {
+            let Some(value) = table.get(&key) else {
+                continue;
+            };
+            if !cfg_key.starts_with("cfg(") {
+                continue;
+            }
+            let cfgs = match &cfgs {
+                Some(cfgs) => cfgs,
+                None => cfgs.insert(target_cfgs(target)?),
+            };
+            if cfg::key_matches(cfg_key, cfgs)? {
+                flags.extend(string_list(&format!("target.{cfg_key}.{key}"), value)?);
+            }
+        }
+    }
+    if !flags.is_empty() {
+        return Ok(flags);
+    }
+
+    Ok(cargo_config_get_list(&format!("build.{key}"))?.unwrap_or_default())
+}
+
+/// Determines the rustflags for the given target, the same way cargo does.
+pub fn get_rustflags(target: &str) -> Result<Vec<String>> {
+    get_flags(Flags::Rustc, target)
+}
+
+/// Determines the rustdocflags for the given target, the same way cargo does.
+pub fn get_rustdocflags(target: &str) -> Result<Vec<String>> {
+    get_flags(Flags::Rustdoc, target)
+}
+
+/// Returns the changes to the `std` features configured in `careful.std-features` (which also
+/// covers `CARGO_CAREFUL_STD_FEATURES`), in the syntax of `-Zcareful-std-features`.
+pub fn get_std_feature_changes() -> Result<Vec<String>> {
+    Ok(cargo_config_get_list("careful.std-features")?.unwrap_or_default())
+}
+
+/// Returns the patches and overlay directories for the standard library sources configured in
+/// `careful.std-patches` (which also covers `CARGO_CAREFUL_STD_PATCHES`). Relative paths are
+/// resolved the same way as for `build.target`.
+pub fn get_std_patches() -> Result<Vec<PathBuf>> {
+    let Some(patches) = cargo_config_get_list("careful.std-patches")? else {
+        return Ok(vec![]);
+    };
+    let bases = config_list_bases("careful.std-patches")?;
+    patches
+        .into_iter()
+        .enumerate()
+        .map(|(i, patch)| {
+            let path = match bases.get(i).and_then(Option::as_deref) {
+                Some(base) => base.join(patch),
+                None => PathBuf::from(patch),
+            };
+            path.canonicalize()
+                .with_context(|| format!("cannot find std patch `{}`", path.display()))
+        })
+        .collect()
+}
+
+/// Returns the path configured in the given key, if any. Relative paths are resolved the same way
+/// as for `build.target`.
+pub fn get_path(key: &str) -> Result<Option<PathBuf>> {
+    let Some(value) = cargo_config_get(key)? else {
+        return Ok(None);
+    };
+    let serde_json::Value::String(path) = value else {
+        bail!("`{key}` must be a string");
+    };
+    let path = match config_list_bases(key)?.first().and_then(Option::as_deref) {
+        Some(base) => base.join(path),
+        None => PathBuf::from(path),
+    };
+    Ok(Some(path))
+}
+
+/// Determines the directory of the current workspace where cargo keeps intermediate build
+/// artifacts, like the outputs of build scripts. That is the `build.build-dir` if one is
+/// configured, and the target directory otherwise.
+pub fn get_build_dir() -> Result<PathBuf> {
+    let mut cmd = cargo();
+    cmd.args(["metadata", "--format-version", "1", "--no-deps"]);
+    cmd.args(cargo_extra_flags());
+    if let Some(dir) = get_arg_flag_value("--target-dir") {
+        // `cargo metadata` does not take `--target-dir`, but it does honor the env var.
+        cmd.env("CARGO_TARGET_DIR", dir);
+    }
+    let output = cmd.output().context("failed to run `cargo metadata`")?;
+    if !output.status.success() {
+        bail!(
+            "`cargo metadata` failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    let metadata: serde_json::Value =
+        serde_json::from_slice(&output.stdout).context("`cargo metadata` returned invalid JSON")?;
+    // Older cargo versions do not report the build directory.
+    metadata["build_directory"]
+        .as_str()
+        .or_else(|| metadata["target_directory"].as_str())
+        .map(PathBuf::from)
+        .context("`cargo metadata` did not report the target directory")
+}
+
+/// Loads the manifest of the current workspace root, which is where cargo takes the profiles from.
+/// Returns `None` if we are not in a workspace.
+fn workspace_manifest() -> Result<Option<toml::Table>> {
+    let mut cmd = cargo();
+    cmd.args(["locate-project", "--workspace", "--message-format", "plain"]);
+    cmd.args(cargo_extra_flags());
+    let output = cmd
+        .output()
+        .context("failed to run `cargo locate-project`")?;
+    if !output.status.success() {
+        // This fails if there is no `Cargo.toml`, e.g. for `cargo careful setup`.
+        return Ok(None);
+    }
+    let path = String::from_utf8(output.stdout)
+        .context("`cargo locate-project` returned invalid UTF-8")?;
+    let path = path.trim_end();
+    let manifest =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read `{path}`"))?;
+    toml::from_str(&manifest)
+        .map(Some)
+        .with_context(|| format!("failed to parse `{path}`"))
+}
+
+/// Determines a setting of the given profile, the same way cargo does: the cargo configuration
+/// takes precedence over the workspace manifest, and settings that are not set are inherited from
+/// the parent profile. Returns `None` if the setting is not set anywhere.
+fn get_profile_value(profile: &str, key: &str) -> Result<Option<serde_json::Value>> {
+    let manifest = workspace_manifest()?;
+    let get = |profile: &str, key: &str| -> Result<Option<serde_json::Value>> {
+        if let Some(value) = cargo_config_get(&format!("profile.{profile}.{key}"))? {
+            return Ok(Some(value));
+        }
+        let Some(value) = (|| manifest.as_ref()?.get("profile")?.get(profile)?.get(key))() else {
+            return Ok(None);
+        };
+        serde_json::to_value(value)
+            .map(Some)
+            .with_context(|| format!("invalid `profile.{profile}.{key}` in the manifest"))
+    };
+
+    let mut profile = profile.to_owned();
+    // Guard against cycles; cargo rejects those, but it might not have looked at the profile yet.
+    for _ in 0..16 {
+        if let Some(value) = get(&profile, key)? {
+            return Ok(Some(value));
+        }
+        profile = match (profile.as_str(), get(&profile, "inherits")?) {
+            ("dev" | "release", _) => break,
+            (_, Some(serde_json::Value::String(parent))) => parent,
+            (_, Some(_)) => bail!("`profile.{profile}.inherits` must be a string"),
+            ("test", None) => "dev".to_owned(),
+            ("bench", None) => "release".to_owned(),
+            (_, None) => bail!("profile `{profile}` does not specify which profile it inherits"),
+        };
+    }
+    Ok(None)
+}
+
+/// Determines the panic strategy (`unwind` or `abort`) of the given profile.
+pub fn get_profile_panic(profile: &str) -> Result<String> {
+    match get_profile_value(profile, "panic")? {
+        None => Ok("unwind".to_owned()),
+        Some(serde_json::Value::String(panic)) => Ok(panic),
+        Some(_) => bail!("the `panic` setting of profile `{profile}` must be a string"),
+    }
+}
+
+/// Determines whether the given profile uses (non-local) LTO, which needs bitcode for all crates.
+pub fn get_profile_lto(profile: &str) -> Result<bool> {
+    // Values set via the environment are always strings.
+    match get_profile_value(profile, "lto")? {
+        None | Some(serde_json::Value::Bool(false)) => Ok(false),
+        Some(serde_json::Value::Bool(true)) => Ok(true),
+        Some(serde_json::Value::String(lto)) => match lto.as_str() {
+            "false" | "off" => Ok(false),
+            "true" | "fat" | "thin" => Ok(true),
+            _ => bail!("invalid `lto` setting `{lto}` in profile `{profile}`"),
+        },
+        Some(_) => bail!("the `lto` setting of profile `{profile}` must be a boolean or a string"),
+    }
+}
+
+/// Determines the directories that the entries of a list-valued config key are relative to. Like
+/// cargo, we consider values from config files to be relative to the directory containing the
+/// `.cargo` directory, and values from the environment or the command-line to be relative to the
+/// current directory (represented as `None`).
+fn config_list_bases(key: &str) -> Result<Vec<Option<PathBuf>>> {
+    let mut cmd = cargo();
+    cmd.args(["config", "get", key, "--show-origin"]);
+    cmd.args(cargo_extra_flags());
+    let output = cmd.output().context("failed to run `cargo config get`")?;
+    if !output.status.success() {
+        bail!(
+            "`cargo config get {key} --show-origin` failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    let output =
+        String::from_utf8(output.stdout).context("`cargo config get` returned invalid UTF-8")?;
+    // Each value is printed on its own line, followed by a comment with its origin.
+    Ok(output
+        .lines()
+        .filter(|line| line.contains('"'))
+        .map(|line| {
+            let origin = line.rsplit_once(" # ").map(|(_, origin)| origin)?;
+            let path = Path::new(origin);
+            // Config files are `.cargo/config.toml`; the base is the parent of `.cargo`.
+            path.is_absolute()
+                .then(|| path.parent()?.parent().map(Path::to_owned))
+                .flatten()
+        })
+        .collect())
+}
+
+/// Determines the `build.target` configured for cargo (which also covers `CARGO_BUILD_TARGET`),
+/// if any. This can be a list of targets. Paths to target specifications are resolved relative
+/// to where they were configured.
+pub fn get_build_targets() -> Result<Option<Vec<String>>> {
+    let Some(value) = cargo_config_get("build.target")? else {
+        return Ok(None);
+    };
+    let targets = match &value {
+        serde_json::Value::String(target) => vec![target.clone()],
+        serde_json::Value::Array(_) => string_list("build.target", &value)?,
+        _ => bail!("`build.target` must be a string or an array of strings"),
+    };
+    if targets.is_empty() {
+        return Ok(None);
+    }
+    let bases = if targets.iter().any(|t| target::is_spec_file(t)) {
+        config_list_bases("build.target")?
+    } else {
+        vec![]
+    };
+    let targets = targets
+        .into_iter()
+        .enumerate()
+        .map(|(i, t)| target::resolve(t, bases.get(i).and_then(Option::as_deref)))
+        .collect::<Result<_>>()?;
+    Ok(Some(targets))
+}
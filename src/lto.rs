@@ -0,0 +1,69 @@
+//! Determining what the careful sysroot needs to support LTO.
+use anyhow::{bail, Result};
+
+use crate::util::*;
+
+/// The kinds of LTO support a sysroot can provide. Each kind also covers the ones before it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum StdLto {
+    /// The sysroot only contains machine code, like any regular release build. This is enough
+    /// for thin-local LTO and for linking with a linker plugin without including `std`.
+    No,
+    /// The sysroot also embeds bitcode, so that `-Clto` (or `lto` in the profile) works.
+    Bitcode,
+    /// The sysroot is built with `-Clinker-plugin-lto`, so that `std` takes part in
+    /// cross-language LTO.
+    LinkerPlugin,
+}
+
+impl StdLto {
+    /// Parses the value of `-Zcareful-std-lto`.
+    pub fn parse(value: &str) -> Result<Self> {
+        Ok(match value {
+            "no" => StdLto::No,
+            "bitcode" => StdLto::Bitcode,
+            "linker-plugin" => StdLto::LinkerPlugin,
+            _ => bail!("`-Zcareful-std-lto` expects `no`, `bitcode` or `linker-plugin`"),
+        })
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StdLto::No => "no",
+            StdLto::Bitcode => "bitcode",
+            StdLto::LinkerPlugin => "linker-plugin",
+        }
+    }
+
+    /// Determines what the given rustflags need from the sysroot.
+    pub fn required_by(rustflags: &[String]) -> Self {
+        // Like rustc, the last `-Clto` flag wins.
+        let mut lto = false;
+        let mut linker_plugin = false;
+        for (name, value) in codegen_options(rustflags) {
+            match name {
+                // The value of `-Clinker-plugin-lto` can also be the path to the plugin.
+                "linker-plugin-lto" => linker_plugin = codegen_option_enabled(value),
+                "lto" => lto = codegen_option_enabled(value),
+                _ => {}
+            }
+        }
+        if linker_plugin {
+            StdLto::LinkerPlugin
+        } else if lto {
+            StdLto::Bitcode
+        } else {
+            StdLto::No
+        }
+    }
+
+    /// The flags the sysroot is built with to provide this kind of LTO support. These come after
+    /// the flags that cargo sets for the sysroot build (which include `-Cembed-bitcode=no`).
+    pub fn rustflags(self) -> &'static [&'static str] {
+        match self {
+            StdLto::No => &[],
+            StdLto::Bitcode => &["-Cembed-bitcode=yes"],
+            StdLto::LinkerPlugin => &["-Cembed-bitcode=yes", "-Clinker-plugin-lto"],
+        }
+    }
+}
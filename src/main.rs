@@ -1,14 +1,23 @@
+use std::collections::HashSet;
 use std::env;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{self, Command, Stdio};
 
 use anyhow::{anyhow, bail, Context, Result};
-use rustc_build_sysroot::{BuildMode, SysrootBuilder, SysrootConfig};
 use rustc_version::VersionMeta;
 
 #[macro_use]
 mod util;
+mod cache;
+mod cfg;
+mod config;
+mod lto;
+mod patch;
+mod rust_src;
+mod sanitizer;
+mod setup;
+mod target;
 
 use util::*;
 
@@ -19,11 +28,55 @@ const CAREFUL_FLAGS: &[&str] = &[
     "--cfg",
     "careful",
 ];
+/// The features of `std` we enable by default; see `std_features` for how to change them.
 const STD_FEATURES: &[&str] = &["panic-unwind", "backtrace"];
 
+/// The environment variable holding the search path for dynamic libraries on the host.
+const DYLIB_PATH_ENV_VAR: &str = if cfg!(windows) {
+    "PATH"
+} else if cfg!(target_os = "macos") {
+    "DYLD_FALLBACK_LIBRARY_PATH"
+} else {
+    "LD_LIBRARY_PATH"
+};
+
 /// The sanitizer to use when just `-Zcareful-sanitizer` is passed as flag.
 const DEFAULT_SANITIZER: &str = "address";
 
+/// The values accepted by `-Zcareful-std-debuginfo` and `-Zcareful-std-opt-level`; these are
+/// passed on to rustc's `-Cdebuginfo` and `-Copt-level`.
+const DEBUGINFO_LEVELS: &[&str] = &[
+    "0",
+    "1",
+    "2",
+    "none",
+    "line-directives-only",
+    "line-tables-only",
+    "limited",
+    "full",
+];
+const OPT_LEVELS: &[&str] = &["0", "1", "2", "3", "s", "z"];
+
+/// Applies the given changes to the default `std` features. Each change is `+feature` (or just
+/// `feature`) to enable a feature, or `-feature` to disable it; later changes take precedence.
+fn std_features(changes: &[String]) -> Result<Vec<String>> {
+    let mut features: Vec<String> = STD_FEATURES.iter().copied().map(Into::into).collect();
+    for change in changes {
+        let (enable, feature) = match change.strip_prefix('-') {
+            Some(feature) => (false, feature),
+            None => (true, change.strip_prefix('+').unwrap_or(change)),
+        };
+        if feature.is_empty() || feature.contains(|c: char| c.is_whitespace() || c == ',') {
+            bail!("invalid std feature `{change}`");
+        }
+        features.retain(|f| f != feature);
+        if enable {
+            features.push(feature.to_owned());
+        }
+    }
+    Ok(features)
+}
+
 pub fn cargo() -> Command {
     Command::new(env::var_os("CARGO").unwrap_or_else(|| OsString::from("cargo")))
 }
@@ -78,215 +131,193 @@ fn main_thread_checker_path() -> Result<Option<PathBuf>> {
     }
 }
 
-// Computes the extra flags that need to be passed to cargo to make it behave like the current
-// cargo invocation.
-fn cargo_extra_flags() -> Vec<String> {
-    let mut flags = Vec::new();
-    // `-Zunstable-options` is required by `--config`.
-    flags.push("-Zunstable-options".to_string());
-
-    // Forward `--config` flags.
-    let config_flag = "--config";
-    for arg in get_arg_flag_values(config_flag) {
-        flags.push(config_flag.to_string());
-        flags.push(arg);
-    }
-
-    // Forward `--manifest-path`.
-    let manifest_flag = "--manifest-path";
-    if let Some(manifest) = get_arg_flag_value(manifest_flag) {
-        flags.push(manifest_flag.to_string());
-        flags.push(manifest);
-    }
-
-    // Forwarding `--target-dir` would make sense, but `cargo metadata` does not support that flag.
-
-    flags
+/// The subcommand that only builds what the given `run` or `test` subcommand would run.
+fn build_only_subcommand(subcommand: &[String]) -> Option<Vec<String>> {
+    let build_only = match subcommand {
+        [cmd] if cmd == "run" || cmd == "r" => vec!["build"],
+        [cmd] if cmd == "test" || cmd == "t" => vec![cmd.as_str(), "--no-run"],
+        [cmd, verb] if cmd == "nextest" && verb == "run" => vec!["nextest", "run", "--no-run"],
+        _ => return None,
+    };
+    Some(build_only.into_iter().map(String::from).collect())
 }
 
-pub fn get_rustflags() -> Vec<String> {
-    // Highest precedence: the encoded env var.
-    if let Ok(rustflags) = env::var("CARGO_ENCODED_RUSTFLAGS") {
-        return if rustflags.is_empty() {
-            vec![]
-        } else {
-            rustflags.split('\x1f').map(Into::into).collect()
-        };
-    }
-
-    // Next: the old var.
-    if let Ok(a) = env::var("RUSTFLAGS") {
-        // This code is taken from `RUSTFLAGS` handling in cargo.
-        return a
-            .split(' ')
-            .map(str::trim)
-            .filter(|s| !s.is_empty())
-            .map(str::to_string)
-            .collect();
+/// Prepares the cargo invocation that does the real work for one target.
+fn careful_cmd(
+    subcommand: &[String],
+    cargo_args: &[String],
+    id: &setup::SysrootId,
+    explicit_target: bool,
+    leaks: bool,
+    rustflags: Vec<String>,
+    sysroot: PathBuf,
+) -> Result<Command> {
+    let target = id.target.as_str();
+    let sanitizer = id.sanitizer.as_deref();
+    let rustdocflags = config::get_rustdocflags(target)?;
+
+    // The flags that make rustc and rustdoc use the careful sysroot.
+    let mut sysroot_flags: Vec<OsString> = vec!["--sysroot".into(), sysroot.into()];
+    if let Some(san) = sanitizer {
+        sysroot_flags.extend(sanitizer::rustflags(san).into_iter().map(Into::into));
     }
+    // User-provided flags must come after CAREFUL_FLAGS so that they can be overridden.
+    let mut flags: Vec<OsString> = CAREFUL_FLAGS.iter().map(Into::into).collect();
+    flags.extend(rustflags.into_iter().map(Into::into));
+    flags.extend(sysroot_flags.iter().cloned());
+    // rustdoc does not get the user's rustflags, just like in a regular cargo invocation.
+    let mut docflags: Vec<OsString> = CAREFUL_FLAGS.iter().map(Into::into).collect();
+    docflags.extend(rustdocflags.into_iter().map(Into::into));
+    docflags.extend(sysroot_flags);
 
-    // As fallback, ask `cargo config`.
-    // FIXME: This does not take into account `target.rustflags`.
     let mut cmd = cargo();
-    cmd.args(["config", "build.rustflags", "--format=json-value"]);
-    cmd.args(cargo_extra_flags());
-    let output = cmd.output().expect("failed to run `cargo config`");
-    if !output.status.success() {
-        // This can fail if the variable is not set.
-        return vec![];
-    }
-    serde_json::from_slice(&output.stdout).unwrap()
-}
+    cmd.args(target::cargo_flags(target));
+    cmd.args(subcommand);
 
-/// Returns whether the given sanitizer is supported on this target.
-///
-/// # Errors
-/// Returns `Err` if there was an error when getting the list of supported sanitizers.
-pub fn sanitizer_supported(san: &str, target: &str) -> Result<bool> {
-    // To get the list of supported sanitizers, we call `rustc --print target-spec-json`
-    // and parse the output.
-
-    let mut cmd = rustc();
-    cmd.args([
-        "-Z",
-        "unstable-options",
-        "--print",
-        "target-spec-json",
-        "--target",
-        target,
-    ]);
-    let output = cmd
-        .output()
-        .context("rustc --print target-spec-json` failed to run")?;
-
-    let output_str = String::from_utf8(output.stdout)
-        .context("`rustc --print target-spec-json` returned invalid UTF-8")?;
-
-    let json: serde_json::Value = serde_json::from_str(&output_str)
-        .context("`rustc --print target-spec-json` output is invalid JSON")?;
-
-    let map = json
-        .as_object()
-        .context("Target spec JSON has unexpected structure")?;
-
-    // The list of supported sanitizers is stored as an array
-    // in the "supported-sanitizers" key of the target JSON
-    match map.get("supported-sanitizers") {
-        Some(serde_json::Value::Array(arr)) => Ok(arr
-            .iter()
-            .any(|v| matches!(&v, &serde_json::Value::String(s) if s == san))),
-        Some(_) => {
-            bail!("Contents of \"supported-sanitizers\" key in target spec JSON are of unexpected type")
-        }
-        None => Ok(false),
+    // We strip all `--target` flags from the user's arguments, and pass the one we are currently
+    // working on. Without an explicit target, we still pass `--target` when using a sanitizer, a
+    // panic=abort sysroot or a dynamic `std`, to avoid using them for build scripts and proc
+    // macros (which are always built with panic=unwind, and must not be instrumented).
+    if explicit_target || sanitizer.is_some() || id.panic_abort || id.std_dylib {
+        cmd.args(["--target", target]);
     }
-}
 
-fn build_sysroot(
-    auto: bool,
-    target: &str,
-    rustc_version: &VersionMeta,
-    rustflags: &[String],
-    sanitizer: Option<&str>,
-    verbose: usize,
-) -> PathBuf {
-    // Determine where the rust sources are located.  The env var manually setting the source
-    // trumps auto-detection.
-    let rust_src = std::env::var_os("RUST_LIB_SRC");
-    let rust_src = match rust_src {
-        Some(path) => {
-            let path = PathBuf::from(path);
-            // Make path absolute if possible.
-            path.canonicalize().unwrap_or(path)
-        }
-        None => {
-            // Check for `rust-src` rustup component.
-            let rustup_src = rustc_build_sysroot::rustc_sysroot_src(rustc())
-                .expect("could not determine sysroot source directory");
-            if !rustup_src.exists() {
-                // Ask the user to install the `rust-src` component, and use that.
-                let mut cmd = Command::new("rustup");
-                cmd.args(["component", "add", "rust-src"]);
-                ask_to_run(
-                    cmd,
-                    auto,
-                    "install the `rust-src` component for the selected toolchain",
-                );
-            }
-            rustup_src
+    // Enable Main Thread Checker on macOS targets, as documented here:
+    // <https://developer.apple.com/documentation/xcode/diagnosing-memory-thread-and-crash-issues-early#Detect-improper-UI-updates-on-background-threads>
+    //
+    // On iOS, tvOS and watchOS simulators, the path is somewhere inside the
+    // simulator runtime, which is more difficult to find, so we don't do that
+    // yet (those target also probably wouldn't run in `cargo-careful` anyway).
+    //
+    // Note: The main thread checker by default removes itself from
+    // `DYLD_INSERT_LIBRARIES` upon load, see `MTC_RESET_INSERT_LIBRARIES`:
+    // <https://bryce.co/main-thread-checker-configuration/#mtc_reset_insert_libraries>
+    // This means that it is not inherited by child processes, so we have to
+    // tell Cargo to set this environment variable for the processes it
+    // launches (instead of just setting it for Cargo itself using `cmd.env`).
+    //
+    // Note: We do this even if the host is not running macOS, even though the
+    // environment variable will also be passed to any rustc processes that
+    // Cargo spawns (as Cargo doesn't currently have a good way of only
+    // specifying environment variables to only the binary being run).
+    // This is probably fine though, the environment variable is
+    // Apple-specific and will likely be ignored on other hosts.
+    if target::is_macos(target)? {
+        if let Some(path) = main_thread_checker_path()? {
+            cmd.arg("--config");
+            // TODO: Quote the path correctly according to toml rules
+            cmd.arg(format!("env.DYLD_INSERT_LIBRARIES={path:?}"));
         }
-    };
+    }
 
-    // Determine where to put the sysroot.
-    let user_dirs = directories::ProjectDirs::from("de", "ralfj", "cargo-careful").unwrap();
-    let mut sysroot_dir: PathBuf = user_dirs.cache_dir().to_owned();
+    cmd.args(cargo_args);
 
-    // From rust/src/bootstrap/config.rs
-    // https://github.com/rust-lang/rust/blob/25b5af1b3a0b9e2c0c57b223b2d0e3e203869b2c/src/bootstrap/config.rs#L549-L555
-    let no_std = target.contains("-none")
-        || target.contains("nvptx")
-        || target.contains("switch")
-        || target.contains("-uefi");
+    // Setup environment. Both rustc and rustdoc need the careful flags.
+    cmd.env(
+        "CARGO_ENCODED_RUSTFLAGS",
+        rustc_build_sysroot::encode_rustflags(&flags),
+    );
+    cmd.env(
+        "CARGO_ENCODED_RUSTDOCFLAGS",
+        rustc_build_sysroot::encode_rustflags(&docflags),
+    );
 
     if let Some(san) = sanitizer {
-        // Use a separate sysroot dir, to get separate caching of builds with and without sanitizer.
-        sysroot_dir.push(san);
-        eprint!("Preparing a careful sysroot (target: {target}, sanitizer: {san})... ")
-    } else {
-        eprint!("Preparing a careful sysroot (target: {target})... ")
-    }
-    // By default, the output gets captured. But sometimes we want to show it to the user.
-    let show_output = verbose > 0 || !auto;
-    if show_output {
-        eprintln!();
+        sanitizer::configure_runtime(&mut cmd, san, leaks, &id.dir())?;
     }
-    let mut builder = SysrootBuilder::new(&sysroot_dir, target)
-        .build_mode(BuildMode::Build)
-        .rustc_version(rustc_version.clone())
-        .cargo({
-            let mut cmd = cargo();
-            if show_output {
-                cmd.stdout(process::Stdio::inherit());
-                cmd.stderr(process::Stdio::inherit());
-            }
-            cmd
-        })
-        .sysroot_config(if no_std {
-            SysrootConfig::NoStd
-        } else {
-            SysrootConfig::WithStd {
-                std_features: STD_FEATURES.iter().copied().map(Into::into).collect(),
-            }
-        })
-        // User-provided flags must come after CAREFUL_FLAGS so that they can be overridden.
-        .rustflags(CAREFUL_FLAGS)
-        .rustflags(rustflags);
 
-    if let Some(san) = sanitizer {
-        builder = builder.rustflag(format!("-Zsanitizer={san}"));
+    Ok(cmd)
+}
+
+/// Determines the cargo profile selected by the command-line flags.
+fn active_profile(is_test: bool) -> String {
+    // `cargo nextest` calls this flag `--cargo-profile`.
+    if let Some(profile) =
+        get_arg_flag_value("--profile").or_else(|| get_arg_flag_value("--cargo-profile"))
+    {
+        profile
+    } else if num_arg_flag("--release") + num_arg_flag("-r") > 0 {
+        "release".into()
+    } else if is_test {
+        "test".into()
+    } else {
+        "dev".into()
     }
-    builder
-        .build_from_source(&rust_src)
-        .expect("failed to build sysroot; run `cargo careful setup` to see what went wrong");
+}
 
-    if !show_output {
-        eprintln!("done");
+/// Determines the targets to work on, and whether they were given explicitly (rather than
+/// defaulting to the host).
+fn selected_targets(rustc_version: &VersionMeta) -> Result<(Vec<String>, bool)> {
+    let cli_targets = get_arg_flag_values("--target")
+        .map(|t| target::resolve(t, None))
+        .collect::<Result<Vec<_>>>()?;
+    let (mut targets, explicit_target) = if !cli_targets.is_empty() {
+        (cli_targets, true)
+    } else if let Some(targets) = config::get_build_targets()? {
+        // Cargo would pick up the configured targets by itself, so these count as explicit.
+        (targets, true)
     } else {
-        eprintln!("A sysroot is now available in `{}`.", sysroot_dir.display());
+        (vec![rustc_version.host.clone()], false)
+    };
+    // Like cargo, ignore duplicate targets.
+    let mut seen = HashSet::new();
+    targets.retain(|t| seen.insert(t.clone()));
+    Ok((targets, explicit_target))
+}
+
+/// Lists the sanitizers supported by the selected targets.
+fn cargo_careful_sanitizers() -> Result<()> {
+    let (targets, _) = selected_targets(&rustc_version_info())?;
+    for target in targets {
+        let sanitizers = target::supported_sanitizers(&target)
+            .with_context(|| format!("failed to get the sanitizers supported by `{target}`"))?;
+        if sanitizers.is_empty() {
+            println!("{target}: no sanitizers supported");
+        } else {
+            println!("{target}: {}", sanitizers.join(", "));
+        }
     }
+    Ok(())
+}
 
-    sysroot_dir
+/// Checks that the sanitizers in a `-Zcareful-sanitizer` list can be used together, one after the
+/// other.
+fn check_sanitizer_list(sanitizers: &[String], leaks: bool) -> Result<()> {
+    let mut seen = HashSet::new();
+    for san in sanitizers {
+        if !seen.insert(san) {
+            bail!("sanitizer `{san}` is listed more than once");
+        }
+        if leaks && san != "address" && san != "leak" {
+            bail!("`-Zcareful-leaks` needs the `address` or `leak` sanitizer, but `{san}` was requested");
+        }
+    }
+    if seen.contains(&"address".to_owned()) && seen.contains(&"leak".to_owned()) {
+        bail!(
+            "the `address` sanitizer already includes `leak`; use `-Zcareful-leaks` to detect leaks with it"
+        );
+    }
+    Ok(())
 }
 
 fn cargo_careful(args: env::Args) -> Result<()> {
     let mut args = args.peekable();
 
+    match args.peek().map(String::as_str) {
+        Some("sysroot") => {
+            args.next();
+            cache::cargo_careful_sysroot(args).unwrap_or_else(|e| show_error!("{e:#}"));
+            return Ok(());
+        }
+        Some("sanitizers") => {
+            cargo_careful_sanitizers().unwrap_or_else(|e| show_error!("{e:#}"));
+            return Ok(());
+        }
+        _ => {}
+    }
+
     let rustc_version = rustc_version_info();
-    let (target, explicit_target) = if let Some(target) = get_arg_flag_value("--target") {
-        (target, true)
-    } else {
-        (rustc_version.host.clone(), false)
-    };
+    let (targets, explicit_target) = selected_targets(&rustc_version)?;
 
     let verbose = num_arg_flag("-v");
 
@@ -305,27 +336,96 @@ fn cargo_careful(args: env::Args) -> Result<()> {
         }
         _ =>
             show_error!(
-                "`cargo careful` supports the following subcommands: `run`, `test`, `build`, `rustc`, `nextest`, and `setup`."
+                "`cargo careful` supports the following subcommands: `run`, `test`, `build`, `rustc`, `nextest`, `setup`, `sysroot`, and `sanitizers`."
             ),
     };
 
-    let mut san_to_try = None;
-    let rustflags = get_rustflags();
+    // The sanitizers to run with, one after the other.
+    let mut sanitizers = Vec::new();
+    let mut leaks = false;
+    // `None` means "determine from the target spec".
+    let mut with_std = None;
+    // Flags that only affect how the sysroot is built.
+    let mut std_rustflags = Vec::new();
+    // `None` means "determine from the profile".
+    let mut panic_abort = None;
+    // `None` means "determine from the profile and the rustflags".
+    let mut std_lto = None;
+    // `None` means "determine from the rustflags".
+    let mut std_dylib = None;
+    // The patches from the command-line come after those from the config.
+    let mut std_patches = config::get_std_patches()?;
+    // Changes to the default `std` features; the command-line comes after the config.
+    let mut std_feature_changes = config::get_std_feature_changes()?;
+    let mut check_src_version = true;
 
     // Go through the args to figure out what is for cargo and what is for us.
     let mut cargo_args = Vec::new();
-    for arg in args.by_ref() {
+    while let Some(arg) = args.next() {
         if let Some(careful_arg) = arg.strip_prefix("-Zcareful-") {
             let (key, value): (&str, Option<&str>) = match careful_arg.split_once('=') {
                 Some((key, value)) => (key, Some(value)),
                 None => (careful_arg, None),
             };
             match (key, value) {
-                ("sanitizer", Some(san)) => san_to_try = Some(san.to_owned()),
-                ("sanitizer", None) => san_to_try = Some(DEFAULT_SANITIZER.to_owned()),
+                ("sanitizer", Some(list)) => {
+                    sanitizers = list.split(',').map(str::to_owned).collect()
+                }
+                ("sanitizer", None) => sanitizers = vec![DEFAULT_SANITIZER.to_owned()],
+                ("leaks", None) => leaks = true,
+                ("leaks", Some(_)) => show_error!("`-Zcareful-leaks` does not take a value"),
+                ("skip-src-version-check", None) => check_src_version = false,
+                ("skip-src-version-check", Some(_)) => {
+                    show_error!("`-Zcareful-skip-src-version-check` does not take a value")
+                }
+                ("std", Some("yes")) => with_std = Some(true),
+                ("std", Some("no")) => with_std = Some(false),
+                ("std", _) => show_error!("`-Zcareful-std` expects `yes` or `no`"),
+                ("panic", Some("abort")) => panic_abort = Some(true),
+                ("panic", Some("unwind")) => panic_abort = Some(false),
+                ("panic", _) => show_error!("`-Zcareful-panic` expects `abort` or `unwind`"),
+                ("std-patch", Some(path)) => std_patches.push(
+                    Path::new(path)
+                        .canonicalize()
+                        .unwrap_or_else(|e| show_error!("cannot find std patch `{path}`: {e}")),
+                ),
+                ("std-patch", None) => show_error!("`-Zcareful-std-patch` expects a path"),
+                ("std-dylib", Some("yes")) => std_dylib = Some(true),
+                ("std-dylib", Some("no")) => std_dylib = Some(false),
+                ("std-dylib", _) => show_error!("`-Zcareful-std-dylib` expects `yes` or `no`"),
+                ("std-lto", Some(value)) => {
+                    std_lto = Some(lto::StdLto::parse(value).unwrap_or_else(|e| show_error!("{e}")))
+                }
+                ("std-lto", None) => show_error!("`-Zcareful-std-lto` expects a value"),
+                ("std-features", Some(changes)) => {
+                    std_feature_changes.extend(changes.split(',').map(str::to_owned))
+                }
+                ("std-features", None) => show_error!(
+                    "`-Zcareful-std-features` expects a comma-separated list of `+feature` or `-feature`"
+                ),
+                ("std-debuginfo", Some(level)) if DEBUGINFO_LEVELS.contains(&level) => {
+                    std_rustflags.push(format!("-Cdebuginfo={level}"))
+                }
+                ("std-debuginfo", _) => show_error!(
+                    "`-Zcareful-std-debuginfo` expects one of {}",
+                    DEBUGINFO_LEVELS.join(", ")
+                ),
+                ("std-opt-level", Some(level)) if OPT_LEVELS.contains(&level) => {
+                    std_rustflags.push(format!("-Copt-level={level}"))
+                }
+                ("std-opt-level", _) => show_error!(
+                    "`-Zcareful-std-opt-level` expects one of {}",
+                    OPT_LEVELS.join(", ")
+                ),
                 _ => show_error!("unsupported careful flag `{}`", arg),
             }
             continue;
+        } else if arg == "--target" {
+            // We pass the target to cargo ourselves, one at a time.
+            args.next();
+            continue;
+        } else if arg.starts_with("--target=") {
+            continue;
         } else if arg == "--" {
             // The rest is definitely not for us.
             break;
@@ -339,104 +439,260 @@ fn cargo_careful(args: env::Args) -> Result<()> {
     cargo_args.push("--".into());
     cargo_args.extend(args);
 
-    let sanitizer = san_to_try.and_then(|san| {
-        sanitizer_supported(&san, &target).map_or_else(
-            |e| {
-                show_error!("failed to get list supported sanitizers: {e}");
-            },
-            |b| {
-                if b {
-                    eprintln!("Using sanitizier `{san}`.");
-                    Some(san)
-                } else {
-                    show_error!("sanitizer `{san}` not supported by target `{target}`");
-                }
-            },
-        )
-    });
-
-    // Let's get ourselves as sysroot.
-    let sysroot = build_sysroot(
-        /*auto*/ subcommand.is_some(),
-        &target,
-        &rustc_version,
-        &rustflags,
-        sanitizer.as_deref(),
-        verbose,
-    );
-    let subcommand = match subcommand {
-        Some(c) => c,
+    let std_features = std_features(&std_feature_changes).unwrap_or_else(|e| show_error!("{e}"));
+
+    // Tests only honor the panic strategy of their profile with `-Zpanic-abort-tests`; otherwise
+    // they are always built with panic=unwind.
+    let is_test = subcommand
+        .as_ref()
+        .is_some_and(|s| matches!(s[0].as_str(), "test" | "t" | "nextest"));
+    let is_run_or_test = is_test
+        || subcommand
+            .as_ref()
+            .is_some_and(|s| matches!(s[0].as_str(), "run" | "r"));
+    let panic_abort_tests = num_arg_flag("-Zpanic-abort-tests") > 0;
+    let profile = active_profile(is_test);
+    let panic_explicit = panic_abort.is_some();
+    let panic_abort = match panic_abort {
+        Some(panic_abort) => panic_abort,
+        None if is_test && !panic_abort_tests => false,
         None => {
-            // We just did the setup.
-            return Ok(());
+            config::get_profile_panic(&profile).unwrap_or_else(|e| {
+                show_error!("failed to determine the panic strategy of profile `{profile}`: {e:#}")
+            }) == "abort"
         }
     };
-
-    // Invoke cargo for the real work.
-    let mut flags: Vec<OsString> = CAREFUL_FLAGS.iter().map(Into::into).collect();
-    // User-provided flags must come after CAREFUL_FLAGS so that they can be overridden.
-    flags.extend(rustflags.into_iter().map(Into::into));
-    flags.push("--sysroot".into());
-    flags.push(sysroot.into());
-    if let Some(san) = sanitizer.as_deref() {
-        flags.push(format!("-Zsanitizer={san}").into());
+    if panic_abort && is_test && !panic_abort_tests {
+        show_error!("running tests with panic=abort requires `-Zpanic-abort-tests`");
     }
+    let profile_lto = config::get_profile_lto(&profile).unwrap_or_else(|e| {
+        show_error!("failed to determine the LTO setting of profile `{profile}`: {e:#}")
+    });
 
-    let mut cmd = cargo();
-    cmd.args(subcommand);
-
-    // Avoids using sanitizers for build scripts and proc macros.
-    if !explicit_target && sanitizer.is_some() {
-        cmd.args(["--target", target.as_str()]);
+    // Leak detection works with AddressSanitizer; without a sanitizer, we use the standalone
+    // LeakSanitizer.
+    if leaks && sanitizers.is_empty() {
+        sanitizers = vec!["leak".to_owned()];
     }
-
-    // Enable Main Thread Checker on macOS targets, as documented here:
-    // <https://developer.apple.com/documentation/xcode/diagnosing-memory-thread-and-crash-issues-early#Detect-improper-UI-updates-on-background-threads>
-    //
-    // On iOS, tvOS and watchOS simulators, the path is somewhere inside the
-    // simulator runtime, which is more difficult to find, so we don't do that
-    // yet (those target also probably wouldn't run in `cargo-careful` anyway).
-    //
-    // Note: The main thread checker by default removes itself from
-    // `DYLD_INSERT_LIBRARIES` upon load, see `MTC_RESET_INSERT_LIBRARIES`:
-    // <https://bryce.co/main-thread-checker-configuration/#mtc_reset_insert_libraries>
-    // This means that it is not inherited by child processes, so we have to
-    // tell Cargo to set this environment variable for the processes it
-    // launches (instead of just setting it for Cargo itself using `cmd.env`).
-    //
-    // Note: We do this even if the host is not running macOS, even though the
-    // environment variable will also be passed to any rustc processes that
-    // Cargo spawns (as Cargo doesn't currently have a good way of only
-    // specifying environment variables to only the binary being run).
-    // This is probably fine though, the environment variable is
-    // Apple-specific and will likely be ignored on other hosts.
-    if target.contains("-darwin") {
-        if let Some(path) = main_thread_checker_path()? {
-            cmd.arg("--config");
-            // TODO: Quote the path correctly according to toml rules
-            cmd.arg(format!("env.DYLD_INSERT_LIBRARIES={path:?}"));
+    check_sanitizer_list(&sanitizers, leaks).unwrap_or_else(|e| show_error!("{e}"));
+    for san in &sanitizers {
+        for target in &targets {
+            match target::sanitizer_supported(san, target) {
+                Err(e) => show_error!("failed to get list supported sanitizers: {e}"),
+                Ok(false) => show_error!("sanitizer `{san}` not supported by target `{target}`"),
+                Ok(true) => {}
+            }
         }
+        eprintln!("Using sanitizier `{san}`.");
     }
+    // `None` means "no sanitizer".
+    let sanitizers: Vec<Option<String>> = if sanitizers.is_empty() {
+        vec![None]
+    } else {
+        sanitizers.into_iter().map(Some).collect()
+    };
 
-    cmd.args(cargo_args);
-
-    // Setup environment. Both rustc and rustdoc need these flags.
-    cmd.env(
-        "CARGO_ENCODED_RUSTFLAGS",
-        rustc_build_sysroot::encode_rustflags(&flags),
-    );
-    cmd.env(
-        "CARGO_ENCODED_RUSTDOCFLAGS",
-        rustc_build_sysroot::encode_rustflags(&flags),
-    );
-
-    // Leaks are not a memory safety issue, don't detect them by default
-    if sanitizer.as_deref() == Some("address") && env::var_os("ASAN_OPTIONS").is_none() {
-        cmd.env("ASAN_OPTIONS", "detect_leaks=0");
+    // Let's get ourselves a sysroot for each target. Since `--sysroot` is a single flag, we then
+    // invoke cargo once per target.
+    let mut cmds = Vec::new();
+    for target in &targets {
+        let rustflags = config::get_rustflags(target)?;
+        let no_std = match with_std {
+            Some(with_std) => !with_std,
+            None => !target::has_std(target).unwrap_or_else(|e| {
+                show_error!("failed to determine whether target `{target}` supports std: {e}")
+            }),
+        };
+        let std_features = (!no_std).then(|| std_features.clone());
+        // Make sure the sysroot has what LTO needs; the default sysroot build does not embed
+        // bitcode, which makes LTO fail when linking.
+        let lto_required = lto::StdLto::required_by(&rustflags).max(if profile_lto {
+            lto::StdLto::Bitcode
+        } else {
+            lto::StdLto::No
+        });
+        let lto = std_lto.unwrap_or(lto_required);
+        if lto < lto_required {
+            show_error!(
+                "the build needs a sysroot with LTO support `{}`, but `-Zcareful-std-lto={}` was requested",
+                lto_required.name(),
+                lto.name(),
+            );
+        }
+        let mut std_rustflags = std_rustflags.clone();
+        std_rustflags.extend(lto.rustflags().iter().copied().map(String::from));
+        // `-Cprefer-dynamic` needs the dynamic `std` library.
+        let std_dylib = std_dylib.unwrap_or_else(|| {
+            codegen_options(&rustflags)
+                .filter(|(name, _)| *name == "prefer-dynamic")
+                .last()
+                .is_some_and(|(_, value)| codegen_option_enabled(value))
+        });
+        // Each sanitizer needs its own sysroot, and a separate run.
+        for sanitizer in &sanitizers {
+            let options = setup::SysrootOptions {
+                std_rustflags: std_rustflags.clone(),
+                std_features: std_features.clone(),
+                sanitizer: sanitizer.clone(),
+                panic_abort,
+                std_dylib,
+                std_patches: std_patches.clone(),
+            };
+            let id = setup::SysrootId::new(&rustc_version, target, &rustflags, &options)
+                .unwrap_or_else(|e| show_error!("{e:#}"));
+            let sysroot = setup::build_sysroot(
+                /*auto*/ subcommand.is_some(),
+                check_src_version,
+                &id,
+                &rustc_version,
+                verbose,
+            );
+            if let Some(subcommand) = &subcommand {
+                let mut cmd = careful_cmd(
+                    subcommand,
+                    &cargo_args,
+                    &id,
+                    explicit_target,
+                    leaks,
+                    rustflags.clone(),
+                    sysroot.clone(),
+                )?;
+                if id.std_dylib && is_run_or_test {
+                    // Make sure the program loads the careful `std`.
+                    let lib_dir = setup::lib_dir(&id.dir(), target);
+                    let mut paths = vec![lib_dir];
+                    match env::var_os(DYLIB_PATH_ENV_VAR) {
+                        Some(user) if !user.is_empty() => paths.extend(env::split_paths(&user)),
+                        _ if cfg!(target_os = "macos") => {
+                            // Setting the variable replaces dyld's default fallback paths, so
+                            // keep them (like cargo does).
+                            paths.extend(
+                                env::var_os("HOME").map(|home| Path::new(&home).join("lib")),
+                            );
+                            paths.push("/usr/local/lib".into());
+                            paths.push("/usr/lib".into());
+                        }
+                        _ => {}
+                    }
+                    let paths = env::join_paths(paths)
+                        .context("failed to construct the library search path")?;
+                    cmd.env(DYLIB_PATH_ENV_VAR, paths);
+                }
+                if panic_explicit {
+                    // Make the profile match the sysroot.
+                    cmd.env(
+                        format!(
+                            "CARGO_PROFILE_{}_PANIC",
+                            profile.to_uppercase().replace('-', "_")
+                        ),
+                        if panic_abort { "abort" } else { "unwind" },
+                    );
+                }
+                // With MSan, we check what gets linked into the program before running it, so we
+                // first build it with the same arguments (up to those for the program) and
+                // environment.
+                let build_cmd = match build_only_subcommand(subcommand) {
+                    Some(build_subcommand) if sanitizer.as_deref() == Some("memory") => {
+                        let end = cargo_args.iter().position(|arg| arg == "--").unwrap();
+                        let mut build_cmd = careful_cmd(
+                            &build_subcommand,
+                            &cargo_args[..end],
+                            &id,
+                            explicit_target,
+                            leaks,
+                            rustflags.clone(),
+                            sysroot,
+                        )?;
+                        for (key, value) in cmd.get_envs() {
+                            match value {
+                                Some(value) => build_cmd.env(key, value),
+                                None => build_cmd.env_remove(key),
+                            };
+                        }
+                        Some(build_cmd)
+                    }
+                    _ => None,
+                };
+                cmds.push((target, sanitizer.as_deref(), build_cmd, cmd));
+            }
+        }
+    }
+    if subcommand.is_none() {
+        // We just did the setup.
+        return Ok(());
     }
 
     // Run it!
-    exec(cmd, (verbose > 0).then_some("[cargo-careful] "))
+    let verbose = (verbose > 0).then_some("[cargo-careful] ");
+    let run = |cmd: &mut Command| {
+        if let Some(prefix) = verbose {
+            eprintln!("{prefix}{cmd:?}");
+        }
+        cmd.status()
+            .unwrap_or_else(|_| panic!("failed to execute {cmd:?}"))
+    };
+    // With MSan, we look at what got linked into the program after the build.
+    let check_native_libs = |target: &str, sanitizer: Option<&str>| {
+        if sanitizer == Some("memory") {
+            sanitizer::warn_uninstrumented_libs(target, &profile);
+        }
+    };
+    if cmds.len() == 1 {
+        let (target, sanitizer, build_cmd, mut cmd) = cmds.pop().unwrap();
+        if let Some(mut build_cmd) = build_cmd {
+            let status = run(&mut build_cmd);
+            if !status.success() {
+                process::exit(status.code().unwrap_or(-1));
+            }
+            check_native_libs(target, sanitizer);
+            exec(cmd, verbose)
+        }
+        if sanitizer != Some("memory") {
+            exec(cmd, verbose)
+        }
+        // The other subcommands (like `build`) do not run the program, so we check when they are
+        // done.
+        let status = run(&mut cmd);
+        check_native_libs(target, sanitizer);
+        process::exit(status.code().unwrap_or(-1));
+    }
+    let mut failed = false;
+    let mut summary = Vec::new();
+    for (target, sanitizer, build_cmd, mut cmd) in cmds {
+        let what = match sanitizer {
+            Some(san) => format!("target `{target}` with sanitizer `{san}`"),
+            None => format!("target `{target}`"),
+        };
+        eprintln!("[cargo-careful] running for {what}");
+        let success = match build_cmd {
+            Some(mut build_cmd) => {
+                let built = run(&mut build_cmd).success();
+                if built {
+                    check_native_libs(target, sanitizer);
+                }
+                built && run(&mut cmd).success()
+            }
+            None => {
+                let success = run(&mut cmd).success();
+                check_native_libs(target, sanitizer);
+                success
+            }
+        };
+        failed |= !success;
+        summary.push((target, sanitizer, success));
+    }
+    eprintln!("[cargo-careful] summary:");
+    for (target, sanitizer, success) in summary {
+        let result = if success { "ok" } else { "FAILED" };
+        match sanitizer {
+            Some(san) => eprintln!("[cargo-careful]   {target} ({san}): {result}"),
+            None => eprintln!("[cargo-careful]   {target}: {result}"),
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -0,0 +1,191 @@
+//! Applying patches and overlay directories to a private copy of the standard library sources.
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+
+use crate::util::*;
+
+/// The file (in the patched source dir) recording what the sources were prepared from.
+const STAMP_FILE_NAME: &str = ".careful-src-stamp";
+
+/// The sources outside of the `library` folder that the standard library build uses, relative to
+/// the root of the Rust repository: with the `llvm-libunwind` feature, `library/unwind` builds
+/// LLVM's libunwind from there.
+const EXTRA_SRC_DIRS: &[&str] = &["src/llvm-project/libunwind"];
+
+/// Calls `f` for every file below `dir`, with its path relative to `dir`, in a stable order.
+fn for_each_file(dir: &Path, f: &mut impl FnMut(&Path) -> Result<()>) -> Result<()> {
+    fn walk(root: &Path, rel: &Path, f: &mut impl FnMut(&Path) -> Result<()>) -> Result<()> {
+        let mut entries = fs::read_dir(root.join(rel))
+            .with_context(|| format!("failed to read `{}`", root.join(rel).display()))?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for name in entries {
+            let rel = rel.join(name);
+            if root.join(&rel).is_dir() {
+                walk(root, &rel, f)?;
+            } else {
+                f(&rel)?;
+            }
+        }
+        Ok(())
+    }
+    walk(dir, Path::new(""), f)
+}
+
+/// Computes a hash of the contents of the given patches and overlay directories (in order), which
+/// identifies the patched sources.
+pub fn patch_hash(patches: &[PathBuf]) -> Result<String> {
+    let mut hasher = StableHasher::new();
+    for patch in patches {
+        if patch.is_dir() {
+            hasher.str("overlay");
+            for_each_file(patch, &mut |rel| {
+                hasher.bytes(rel.as_os_str().as_encoded_bytes());
+                hasher.bytes(
+                    &fs::read(patch.join(rel)).with_context(|| {
+                        format!("failed to read `{}`", patch.join(rel).display())
+                    })?,
+                );
+                Ok(())
+            })?;
+        } else {
+            hasher.str("patch");
+            hasher.bytes(
+                &fs::read(patch)
+                    .with_context(|| format!("failed to read std patch `{}`", patch.display()))?,
+            );
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// Copies all files from `from` to `to`, replacing existing files.
+fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    for_each_file(from, &mut |rel| {
+        let dest = to.join(rel);
+        fs::create_dir_all(dest.parent().unwrap())
+            .with_context(|| format!("failed to create `{}`", dest.parent().unwrap().display()))?;
+        // The sources can be read-only, so we replace files rather than overwriting them.
+        if dest.exists() {
+            fs::remove_file(&dest)
+                .with_context(|| format!("failed to remove `{}`", dest.display()))?;
+        }
+        fs::copy(from.join(rel), &dest)
+            .with_context(|| format!("failed to copy `{}`", from.join(rel).display()))?;
+        Ok(())
+    })
+}
+
+/// Applies a patch file to the sources in `root`.
+fn apply_patch(root: &Path, patch: &Path) -> Result<()> {
+    let mut cmd = Command::new("patch");
+    cmd.args(["-p1", "--forward", "--batch", "-i"]);
+    cmd.arg(patch);
+    cmd.current_dir(root);
+    let output = cmd.output().context("failed to run `patch`")?;
+    if !output.status.success() {
+        bail!(
+            "failed to apply std patch `{}`:\n{}",
+            patch.display(),
+            String::from_utf8_lossy(&output.stdout).trim_end()
+        );
+    }
+    Ok(())
+}
+
+/// Computes a fingerprint of the sources at `rust_src` from the paths, sizes and modification
+/// times of their files, so that we notice edits without reading every file.
+fn src_fingerprint(rust_src: &Path) -> Result<String> {
+    let mut hasher = StableHasher::new();
+    let mut add = |path: &Path, rel: &Path| -> Result<()> {
+        let metadata =
+            fs::metadata(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        hasher.str(&rel.to_string_lossy());
+        hasher.bytes(&metadata.len().to_le_bytes());
+        hasher.bytes(&modified.as_nanos().to_le_bytes());
+        Ok(())
+    };
+    for_each_file(rust_src, &mut |rel| add(&rust_src.join(rel), rel))?;
+    if let Some(root) = rust_src.parent() {
+        let lock_file = root.join("Cargo.lock");
+        if lock_file.exists() {
+            add(&lock_file, Path::new("../Cargo.lock"))?;
+        }
+        for extra in EXTRA_SRC_DIRS {
+            let extra_dir = root.join(extra);
+            if extra_dir.is_dir() {
+                let prefix = Path::new("..").join(extra);
+                for_each_file(&extra_dir, &mut |rel| {
+                    add(&extra_dir.join(rel), &prefix.join(rel))
+                })?;
+            }
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// Prepares a copy of the standard library sources (the `library` folder at `rust_src`) in `dir`,
+/// with the given patches and overlay directories applied in order. Patches and overlays are
+/// relative to the root of the Rust repository, i.e., they contain paths like
+/// `library/std/src/lib.rs`. The other sources that the build needs (see [`EXTRA_SRC_DIRS`]) are
+/// copied as well. Returns the `library` folder of the copy.
+///
+/// The copy is only recreated when the sources (as far as their file sizes and modification times
+/// tell) or patches change, so that the sysroot builder (which checks modification times) does not
+/// rebuild the sysroot each time.
+pub fn patched_src(
+    rust_src: &Path,
+    patches: &[PathBuf],
+    patch_hash: &str,
+    dir: &Path,
+) -> Result<PathBuf> {
+    let library = dir.join("library");
+    let stamp = format!(
+        "{}\n{}\n{patch_hash}\n",
+        rust_src.display(),
+        src_fingerprint(rust_src).context("failed to fingerprint the standard library sources")?
+    );
+    if fs::read_to_string(dir.join(STAMP_FILE_NAME)).is_ok_and(|s| s == stamp) {
+        return Ok(library);
+    }
+
+    if dir.exists() {
+        fs::remove_dir_all(dir).with_context(|| format!("failed to remove `{}`", dir.display()))?;
+    }
+    copy_dir(rust_src, &library).context("failed to copy the standard library sources")?;
+    if let Some(root) = rust_src.parent() {
+        // With older sources, the lock file lives next to the `library` folder.
+        let lock_file = root.join("Cargo.lock");
+        if lock_file.exists() {
+            fs::copy(lock_file, dir.join("Cargo.lock")).context("failed to copy `Cargo.lock`")?;
+        }
+        for extra in EXTRA_SRC_DIRS {
+            let extra_dir = root.join(extra);
+            if extra_dir.is_dir() {
+                copy_dir(&extra_dir, &dir.join(extra))
+                    .with_context(|| format!("failed to copy `{}`", extra_dir.display()))?;
+            }
+        }
+    }
+    for patch in patches {
+        if patch.is_dir() {
+            copy_dir(patch, dir)
+                .with_context(|| format!("failed to apply std overlay `{}`", patch.display()))?;
+        } else {
+            apply_patch(dir, patch)?;
+        }
+    }
+    // Write the stamp last, so that we start over if anything above fails.
+    fs::write(dir.join(STAMP_FILE_NAME), stamp).context("failed to write source stamp")?;
+    Ok(library)
+}
@@ -0,0 +1,258 @@
+//! Locating the standard library sources that the careful sysroot is built from.
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use rustc_version::VersionMeta;
+
+use crate::rustc;
+use crate::util::*;
+
+/// Determines the sysroot of the current toolchain.
+fn rustc_sysroot() -> Result<PathBuf> {
+    let output = rustc()
+        .args(["--print", "sysroot"])
+        .output()
+        .context("failed to run `rustc --print sysroot`")?;
+    if !output.status.success() {
+        bail!(
+            "`rustc --print sysroot` failed:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    let sysroot = String::from_utf8(output.stdout)
+        .context("`rustc --print sysroot` returned invalid UTF-8")?;
+    Ok(PathBuf::from(sysroot.trim_end()))
+}
+
+/// Whether the toolchain with the given sysroot is managed by rustup (and hence we can ask rustup
+/// to install `rust-src` for it).
+fn is_rustup_toolchain(sysroot: &Path) -> bool {
+    let rustup_home = match env::var_os("RUSTUP_HOME") {
+        Some(home) => PathBuf::from(home),
+        None => match directories::BaseDirs::new() {
+            Some(dirs) => dirs.home_dir().join(".rustup"),
+            None => return false,
+        },
+    };
+    let toolchains = rustup_home.join("toolchains");
+    let toolchains = toolchains.canonicalize().unwrap_or(toolchains);
+    sysroot.starts_with(toolchains)
+}
+
+/// What we know about the version of a `library` source folder.
+#[derive(Default)]
+pub struct SrcVersion {
+    /// The release, like `1.80.0` (without the channel).
+    release: Option<String>,
+    /// The commit, possibly abbreviated.
+    commit: Option<String>,
+}
+
+impl SrcVersion {
+    /// Parses a version string like `1.80.0-nightly (<short hash> <date>)`, as found in source
+    /// tarballs and in the rustup channel manifest.
+    fn parse(version: &str) -> Self {
+        let release = version
+            .split_whitespace()
+            .next()
+            .and_then(|v| v.split('-').next())
+            .filter(|v| !v.is_empty())
+            .map(str::to_owned);
+        let commit = version
+            .split_once('(')
+            .and_then(|(_, details)| details.split_whitespace().next())
+            .map(str::to_owned);
+        SrcVersion { release, commit }
+    }
+
+    /// Whether these sources belong to the given rustc. If we cannot tell, we assume they do.
+    pub fn matches(&self, rustc_version: &VersionMeta) -> bool {
+        if let (Some(commit), Some(rustc_commit)) = (&self.commit, &rustc_version.commit_hash) {
+            return !commit.is_empty() && rustc_commit.starts_with(commit.as_str());
+        }
+        if let Some(release) = &self.release {
+            let v = &rustc_version.semver;
+            return *release == format!("{}.{}.{}", v.major, v.minor, v.patch);
+        }
+        true
+    }
+}
+
+impl fmt::Display for SrcVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.release, &self.commit) {
+            (Some(release), Some(commit)) => write!(f, "{release} (commit {commit})"),
+            (Some(release), None) => write!(f, "{release}"),
+            (None, Some(commit)) => write!(f, "commit {commit}"),
+            (None, None) => write!(f, "an unknown version"),
+        }
+    }
+}
+
+/// Determines the version of the given `library` source folder, as far as we can tell.
+pub fn src_version(library: &Path) -> SrcVersion {
+    let Some(root) = library.parent() else {
+        return SrcVersion::default();
+    };
+    // Source tarballs record the version and the commit.
+    if let Ok(version) = fs::read_to_string(root.join("version")) {
+        let mut version = SrcVersion::parse(version.trim());
+        if let Ok(hash) = fs::read_to_string(root.join("git-commit-hash")) {
+            version.commit = Some(hash.trim().to_owned());
+        }
+        return version;
+    }
+    // A git checkout, which has the release in `src/version`.
+    if root.join(".git").exists() {
+        let release = fs::read_to_string(root.join("src/version"))
+            .ok()
+            .map(|v| v.trim().to_owned());
+        let commit = Command::new("git")
+            .arg("-C")
+            .arg(root)
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|hash| hash.trim().to_owned());
+        return SrcVersion { release, commit };
+    }
+    // The `rust-src` component installed by rustup, in `<sysroot>/lib/rustlib/src/rust/library`:
+    // the channel manifest describes the version of the component.
+    let version = (|| {
+        let rustlib = root.parent()?.parent()?;
+        let manifest = fs::read_to_string(rustlib.join("multirust-channel-manifest.toml")).ok()?;
+        let manifest: toml::Table = toml::from_str(&manifest).ok()?;
+        let version = manifest
+            .get("pkg")?
+            .get("rust-src")?
+            .get("version")?
+            .as_str()?;
+        Some(SrcVersion::parse(version))
+    })();
+    version.unwrap_or_default()
+}
+
+/// Whether `library` is in a git checkout whose `HEAD` contains the commit of the given rustc, as
+/// when working on top of that commit.
+fn is_based_on(library: &Path, rustc_version: &VersionMeta) -> bool {
+    let (Some(root), Some(commit)) = (library.parent(), &rustc_version.commit_hash) else {
+        return false;
+    };
+    root.join(".git").exists()
+        && Command::new("git")
+            .arg("-C")
+            .arg(root)
+            .args(["merge-base", "--is-ancestor", commit, "HEAD"])
+            .output()
+            .is_ok_and(|output| output.status.success())
+}
+
+/// Makes sure the sources at `library` belong to the given rustc, since building a sysroot from
+/// mismatching sources fails with lots of confusing errors.
+fn check_version(library: &Path, rustc_version: &VersionMeta, fix: &str) {
+    let version = src_version(library);
+    if !version.matches(rustc_version) && !is_based_on(library, rustc_version) {
+        show_error!(
+            "the standard library sources in `{}` are from {version}, but the compiler is `{}` \
+             (commit {}).\n{fix}\n\
+             To use these sources anyway, pass `-Zcareful-skip-src-version-check`.",
+            library.display(),
+            rustc_version.short_version_string,
+            rustc_version.commit_hash.as_deref().unwrap_or("unknown"),
+        );
+    }
+}
+
+/// The places where the sources of a toolchain with the given sysroot can be.
+fn candidates(sysroot: &Path) -> Vec<PathBuf> {
+    let mut candidates = vec![
+        // The `rust-src` component, installed by rustup or by a distribution.
+        sysroot.join("lib/rustlib/src/rust/library"),
+        // The `rustc-dev` component also contains the sources.
+        sysroot.join("lib/rustlib/rustc-src/rust/library"),
+    ];
+    // A toolchain built locally with `x.py` has its sysroot somewhere inside the checkout
+    // (usually `build/<host>/stage1`).
+    if let Some(checkout) = sysroot.ancestors().find(|dir| dir.join("x.py").is_file()) {
+        candidates.push(checkout.join("library"));
+    }
+    candidates
+}
+
+/// Determines where the sources of the standard library (the `library` folder) are. The
+/// `RUST_LIB_SRC` env var trumps auto-detection. If no sources are found and the toolchain is
+/// managed by rustup, we offer to install the `rust-src` component (asking first if `ask` is set).
+/// Unless `check_version` is unset, sources that do not belong to the given rustc are rejected.
+pub fn locate(ask: bool, check_version: bool, rustc_version: &VersionMeta) -> PathBuf {
+    let check = |library: &Path, fix: &str| {
+        if check_version {
+            self::check_version(library, rustc_version, fix);
+        }
+    };
+    if let Some(path) = env::var_os("RUST_LIB_SRC") {
+        let path = PathBuf::from(path);
+        // Make path absolute if possible.
+        let path = path.canonicalize().unwrap_or(path);
+        check(
+            &path,
+            "Check out the matching commit in that Rust repository, or unset `RUST_LIB_SRC` to use \
+             the `rust-src` component of the toolchain.",
+        );
+        return path;
+    }
+
+    let sysroot = rustc_sysroot().unwrap_or_else(|e| show_error!("{e:#}"));
+    let sysroot = sysroot.canonicalize().unwrap_or(sysroot);
+    let candidates = candidates(&sysroot);
+    let mut rejected = Vec::new();
+    for candidate in &candidates {
+        if !candidate.join("std/Cargo.toml").exists() {
+            rejected.push(format!("`{}`: not found", candidate.display()));
+            continue;
+        }
+        let version = src_version(candidate);
+        if check_version && !version.matches(rustc_version) {
+            rejected.push(format!("`{}`: from {version}", candidate.display()));
+            continue;
+        }
+        return candidate.clone();
+    }
+
+    let rustup = is_rustup_toolchain(&sysroot);
+    if rustup && !candidates[0].exists() {
+        // Ask the user to install the `rust-src` component, and use that.
+        let mut cmd = Command::new("rustup");
+        cmd.args(["component", "add", "rust-src"]);
+        ask_to_run(
+            cmd,
+            ask,
+            "install the `rust-src` component for the selected toolchain",
+        );
+        check(
+            &candidates[0],
+            "Try updating the toolchain with `rustup update`.",
+        );
+        return candidates[0].clone();
+    }
+    let hint = if rustup {
+        "Reinstall the `rust-src` component with `rustup component remove rust-src` and \
+         `rustup component add rust-src`"
+    } else {
+        "This toolchain is not managed by rustup. Install the `rust-src` component of your \
+         distribution"
+    };
+    show_error!(
+        "could not find the standard library sources for `{}` (commit {}).\n\
+         Looked in:\n  {}\n\
+         {hint}, or set `RUST_LIB_SRC` to the `library` folder of a Rust checkout at the same commit.",
+        rustc_version.short_version_string,
+        rustc_version.commit_hash.as_deref().unwrap_or("unknown"),
+        rejected.join("\n  "),
+    )
+}
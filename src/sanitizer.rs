@@ -0,0 +1,453 @@
+//! Configuring the sanitizer runtimes of the program being checked.
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::util::*;
+use crate::{config, target};
+
+/// Suppressions for ThreadSanitizer reports that are caused by uninstrumented parts of the runtime
+/// rather than by the program. A `race:` suppression hides every report with a matching frame in
+/// either stack, so we do not add any for the standard library.
+const TSAN_SUPPRESSIONS: &str = "\
+# The unwinder is not instrumented.
+called_from_lib:libgcc_s.so
+";
+
+/// Suppressions for LeakSanitizer reports about memory that the standard library leaks on purpose.
+const LSAN_SUPPRESSIONS: &str = "\
+# Values of `OnceLock`, `LazyLock` and `Once`-initialized statics are never freed.
+leak:std::sys::sync::once::
+# Thread-locals whose destructors do not run, like those of the main thread at exit.
+leak:std::sys::thread_local::
+# The runtime sets up some data for the main thread (like its `Thread` handle) that lives until exit.
+leak:std::rt::init
+";
+
+/// The flags that enable the given sanitizer, both for the sysroot and for the program.
+pub fn rustflags(san: &str) -> Vec<String> {
+    let mut flags = vec![format!("-Zsanitizer={san}")];
+    if san == "memory" {
+        // Without this, MSan only reports where uninitialized memory is used, not where it came
+        // from.
+        flags.push("-Zsanitizer-memory-track-origins".into());
+    }
+    flags
+}
+
+/// The runtime options of a sanitizer, as key/value pairs. Setting a key again replaces its value.
+#[derive(Default)]
+struct RuntimeOptions(Vec<(String, String)>);
+
+impl RuntimeOptions {
+    /// Parses options in the syntax of the sanitizer runtimes: `key=value` pairs separated by
+    /// colons, commas or whitespace, where values can be quoted.
+    fn parse(options: &str) -> Result<Self> {
+        let is_separator = |c: char| c == ':' || c == ',' || c.is_whitespace();
+        let mut parsed = RuntimeOptions::default();
+        let mut rest = options.trim_start_matches(is_separator);
+        while !rest.is_empty() {
+            let (key, value) = rest
+                .split_once('=')
+                .with_context(|| format!("expected `key=value` in `{rest}`"))?;
+            let (value, next) = match value.chars().next() {
+                Some(quote @ ('"' | '\'')) => value[1..]
+                    .split_once(quote)
+                    .with_context(|| format!("unterminated quote in `{value}`"))?,
+                _ => value.split_at(value.find(is_separator).unwrap_or(value.len())),
+            };
+            parsed.set(key, value);
+            rest = next.trim_start_matches(is_separator);
+        }
+        Ok(parsed)
+    }
+
+    fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((key.to_owned(), value)),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<String> {
+        let pos = self.0.iter().position(|(k, _)| k == key)?;
+        Some(self.0.remove(pos).1)
+    }
+
+    /// Sets all the given options, replacing the values of keys that are already set.
+    fn merge(&mut self, other: RuntimeOptions) {
+        for (key, value) in other.0 {
+            self.set(&key, value);
+        }
+    }
+}
+
+impl fmt::Display for RuntimeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            let needs_quotes = value.is_empty()
+                || value.contains(|c: char| c == ':' || c == ',' || c.is_whitespace());
+            match (needs_quotes, value.contains('"')) {
+                (false, _) => write!(f, "{key}={value}")?,
+                (true, false) => write!(f, "{key}=\"{value}\"")?,
+                (true, true) => write!(f, "{key}='{value}'")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The name of the runtimes that the given sanitizer uses, which is also the prefix of their
+/// environment variable (like `ASAN_OPTIONS`) and of their cargo config keys (like
+/// `careful.asan-options`). With `leaks`, AddressSanitizer also uses the LeakSanitizer runtime.
+fn runtimes(san: &str, leaks: bool) -> Vec<&'static str> {
+    let runtime = match san {
+        "address" => "asan",
+        "hwaddress" => "hwasan",
+        "leak" => "lsan",
+        "memory" => "msan",
+        "thread" => "tsan",
+        _ => return vec![],
+    };
+    if leaks && runtime != "lsan" {
+        vec![runtime, "lsan"]
+    } else {
+        vec![runtime]
+    }
+}
+
+/// Reads the runtime options configured in the `careful.<runtime>-options` table.
+fn config_options(runtime: &str) -> Result<RuntimeOptions> {
+    let key = format!("careful.{runtime}-options");
+    let mut options = RuntimeOptions::default();
+    match config::cargo_config_get(&key)? {
+        None => {}
+        Some(serde_json::Value::Object(table)) => {
+            for (name, value) in table {
+                let value = match value {
+                    serde_json::Value::String(value) => value,
+                    serde_json::Value::Bool(_) | serde_json::Value::Number(_) => value.to_string(),
+                    _ => bail!("`{key}.{name}` must be a string, a number or a boolean"),
+                };
+                options.set(&name, value);
+            }
+        }
+        Some(_) => bail!("`{key}` must be a table"),
+    }
+    Ok(options)
+}
+
+/// Writes a suppressions file for the given runtime to `dir`: the built-in suppressions, followed
+/// by the contents of the given suppressions files. Returns its path.
+///
+/// The file is named after a hash of its contents and never changes once written, so that runs
+/// with different suppressions can share `dir`.
+fn write_suppressions(
+    runtime: &str,
+    builtin: &str,
+    files: &[PathBuf],
+    dir: &Path,
+) -> Result<PathBuf> {
+    let mut suppressions = builtin.to_owned();
+    for file in files {
+        let contents = fs::read_to_string(file)
+            .with_context(|| format!("failed to read suppressions file `{}`", file.display()))?;
+        suppressions.push_str(&format!("# From `{}`.\n", file.display()));
+        suppressions.push_str(&contents);
+    }
+    let mut hasher = StableHasher::new();
+    hasher.str(&suppressions);
+    let path = dir.join(format!("{runtime}-suppressions-{}.txt", hasher.finish()));
+    if path.exists() {
+        return Ok(path);
+    }
+    // Write to a temporary file first, so that nobody sees a partially written file.
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, suppressions)
+        .with_context(|| format!("failed to write `{}`", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("failed to write `{}`", path.display()))?;
+    Ok(path)
+}
+
+/// Sets up the environment for the runtime of the given sanitizer. If `leaks` is set, leaks are
+/// detected (with AddressSanitizer or LeakSanitizer). `dir` is the sysroot directory, which is
+/// where we keep the generated suppressions files.
+///
+/// Our defaults are combined with the options from the cargo configuration and then with those
+/// from the environment, where later values win for each key. Suppressions files are not replaced
+/// but combined with our own suppressions.
+pub fn configure_runtime(cmd: &mut Command, san: &str, leaks: bool, dir: &Path) -> Result<()> {
+    for runtime in runtimes(san, leaks) {
+        let var = format!("{}_OPTIONS", runtime.to_uppercase());
+        let mut options = RuntimeOptions::default();
+        let builtin_suppressions = match runtime {
+            "asan" => {
+                // Leaks are not a memory safety issue, don't detect them by default
+                options.set("detect_leaks", if leaks { "1" } else { "0" });
+                None
+            }
+            "tsan" => {
+                // Show both stacks in reports about lock-order inversions (of pthread mutexes).
+                options.set("second_deadlock_stack", "1");
+                Some(TSAN_SUPPRESSIONS)
+            }
+            "lsan" => Some(LSAN_SUPPRESSIONS),
+            _ => None,
+        };
+        options.merge(config_options(runtime)?);
+        if let Some(user) = env::var_os(&var) {
+            let user = user
+                .into_string()
+                .map_err(|_| anyhow!("`{var}` is not valid UTF-8"))?;
+            options
+                .merge(RuntimeOptions::parse(&user).with_context(|| format!("invalid `{var}`"))?);
+        }
+
+        if let Some(builtin) = builtin_suppressions {
+            let mut files = Vec::new();
+            files.extend(config::get_path(&format!(
+                "careful.{runtime}-suppressions"
+            ))?);
+            files.extend(options.remove("suppressions").map(PathBuf::from));
+            let suppressions = write_suppressions(runtime, builtin, &files, dir)?;
+            options.set("suppressions", suppressions.to_string_lossy());
+        }
+
+        let options = options.to_string();
+        if num_arg_flag("-v") > 0 {
+            eprintln!("[cargo-careful] {var}={options}");
+        }
+        cmd.env(var, options);
+    }
+    Ok(())
+}
+
+/// The directory (inside the target directory) that cargo uses for the given profile.
+fn profile_dir(profile: &str) -> &str {
+    match profile {
+        "dev" | "test" => "debug",
+        "bench" => "release",
+        profile => profile,
+    }
+}
+
+/// Whether the given static or dynamic library contains code instrumented with MemorySanitizer,
+/// which references the `__msan_*` runtime functions. If we cannot tell, we assume it does.
+fn is_msan_instrumented(lib: &Path) -> bool {
+    let mut cmd = Command::new("nm");
+    if lib.extension().is_some_and(|ext| ext != "a") {
+        cmd.arg("-D");
+    }
+    cmd.arg(lib);
+    match cmd.output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).contains("__msan_")
+        }
+        _ => true,
+    }
+}
+
+/// Finds the native libraries that build scripts link into the program (built for the given
+/// target and profile) which are not instrumented with MemorySanitizer.
+fn uninstrumented_libs(target: &str, profile: &str) -> Result<Vec<(String, PathBuf)>> {
+    let build_dir = config::get_build_dir()?
+        .join(target::short_name(target))
+        .join(profile_dir(profile))
+        .join("build");
+    let Ok(entries) = fs::read_dir(&build_dir) else {
+        return Ok(vec![]);
+    };
+    // The build directory can contain outputs from earlier builds of the same package (with other
+    // features or flags); we only look at the most recent one of each package.
+    let mut outputs: HashMap<String, (SystemTime, PathBuf)> = HashMap::new();
+    for entry in entries {
+        let entry = entry.with_context(|| format!("failed to read `{}`", build_dir.display()))?;
+        let output = entry.path().join("output");
+        let Ok(modified) = fs::metadata(&output).and_then(|m| m.modified()) else {
+            continue;
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let package = name.rsplit_once('-').map_or(name.as_str(), |(p, _)| p);
+        match outputs.get(package) {
+            Some((newest, _)) if *newest >= modified => {}
+            _ => {
+                outputs.insert(package.to_owned(), (modified, output));
+            }
+        }
+    }
+
+    let mut libs = Vec::new();
+    for (_, output) in outputs.values() {
+        let output = fs::read_to_string(output)
+            .with_context(|| format!("failed to read `{}`", output.display()))?;
+        let mut search_paths = Vec::new();
+        let mut names = Vec::new();
+        for line in output.lines() {
+            let Some(directive) = line
+                .strip_prefix("cargo::")
+                .or_else(|| line.strip_prefix("cargo:"))
+            else {
+                continue;
+            };
+            if let Some(path) = directive.strip_prefix("rustc-link-search=") {
+                // Strip the kind, as in `native=<path>`.
+                let path = path.split_once('=').map_or(path, |(_, path)| path);
+                search_paths.push(PathBuf::from(path));
+            } else if let Some(lib) = directive.strip_prefix("rustc-link-lib=") {
+                // Strip the kind and modifiers, as in `static:+whole-archive=<name>`, and the
+                // renaming, as in `<name>:<rename>`.
+                let lib = lib.rsplit_once('=').map_or(lib, |(_, lib)| lib);
+                let lib = lib.split_once(':').map_or(lib, |(lib, _)| lib);
+                names.push(lib.to_owned());
+            }
+        }
+        for name in names {
+            // Libraries that are not in one of the search paths come from the system, and the
+            // MSan runtime intercepts the commonly used ones.
+            let Some(path) = search_paths
+                .iter()
+                .flat_map(|dir| {
+                    [
+                        dir.join(format!("lib{name}.a")),
+                        dir.join(format!("lib{name}.so")),
+                    ]
+                })
+                .find(|path| path.is_file())
+            else {
+                continue;
+            };
+            if !is_msan_instrumented(&path) {
+                libs.push((name, path));
+            }
+        }
+    }
+    libs.sort();
+    libs.dedup();
+    Ok(libs)
+}
+
+/// Warns about native libraries in the program that are not instrumented with MemorySanitizer,
+/// since MSan reports false positives for memory initialized by such code.
+pub fn warn_uninstrumented_libs(target: &str, profile: &str) {
+    let libs = match uninstrumented_libs(target, profile) {
+        Ok(libs) => libs,
+        Err(e) => {
+            eprintln!("warn: failed to check the native libraries for MSan instrumentation: {e:#}");
+            return;
+        }
+    };
+    if libs.is_empty() {
+        return;
+    }
+    for (name, path) in &libs {
+        eprintln!(
+            "warn: native library `{name}` (`{}`) is not instrumented with MemorySanitizer",
+            path.display()
+        );
+    }
+    eprintln!(
+        "      MSan reports false positives for memory initialized by such code. Build it with \
+         `-fsanitize=memory`, e.g. by setting `CC=clang` and `CFLAGS=-fsanitize=memory` for \
+         build scripts that use the `cc` crate."
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(options: &str) -> Vec<(String, String)> {
+        RuntimeOptions::parse(options).unwrap().0
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_separators() {
+        let expected = pairs(&[("a", "1"), ("b", "2"), ("c", "3"), ("d", "4")]);
+        assert_eq!(parse("a=1:b=2,c=3 d=4"), expected);
+        assert_eq!(parse(" :a=1\tb=2\n,c=3::d=4, "), expected);
+        assert_eq!(parse(""), pairs(&[]));
+        // Later values replace earlier ones, but keep their position.
+        assert_eq!(parse("a=1:b=2:a=3"), pairs(&[("a", "3"), ("b", "2")]));
+    }
+
+    #[test]
+    fn parse_quoted_and_empty() {
+        assert_eq!(
+            parse(r#"a="x y:z",b='say "hi"':c=d"#),
+            pairs(&[("a", "x y:z"), ("b", r#"say "hi""#), ("c", "d")])
+        );
+        assert_eq!(
+            parse(r#"a="":b=:c=''"#),
+            pairs(&[("a", ""), ("b", ""), ("c", "")])
+        );
+        // Quotes in the middle of a value are just characters.
+        assert_eq!(parse(r#"a=x"y"#), pairs(&[("a", r#"x"y"#)]));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(RuntimeOptions::parse("a").is_err());
+        assert!(RuntimeOptions::parse("a=1:b").is_err());
+        assert!(RuntimeOptions::parse(r#"a="x"#).is_err());
+        assert!(RuntimeOptions::parse("a='x\"").is_err());
+    }
+
+    #[test]
+    fn display() {
+        let options = |pairs: &[(&str, &str)]| {
+            let mut options = RuntimeOptions::default();
+            for (key, value) in pairs {
+                options.set(key, *value);
+            }
+            options.to_string()
+        };
+        assert_eq!(options(&[]), "");
+        assert_eq!(options(&[("a", "1"), ("b", "/x/y")]), "a=1:b=/x/y");
+        assert_eq!(options(&[("a", "")]), r#"a="""#);
+        assert_eq!(options(&[("a", "x y"), ("b", "x,y")]), r#"a="x y":b="x,y""#);
+        assert_eq!(options(&[("a", r#"say "hi""#)]), r#"a='say "hi"'"#);
+        assert_eq!(options(&[("a", r#"x"y"#)]), r#"a=x"y"#);
+    }
+
+    #[test]
+    fn round_trip() {
+        for values in [
+            &["1", "/path/to/file.txt"][..],
+            &["", "x y", "x:y", "x,y", "x\ty"],
+            &[r#"say "hi""#, r#"x"y"#, "it's"],
+        ] {
+            let mut options = RuntimeOptions::default();
+            for (i, value) in values.iter().enumerate() {
+                options.set(&format!("key{i}"), *value);
+            }
+            assert_eq!(parse(&options.to_string()), options.0);
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let mut options = RuntimeOptions::parse("a=1:b=2").unwrap();
+        options.merge(RuntimeOptions::parse("b=3:c=4").unwrap());
+        assert_eq!(options.0, pairs(&[("a", "1"), ("b", "3"), ("c", "4")]));
+        assert_eq!(options.remove("b").as_deref(), Some("3"));
+        assert_eq!(options.remove("b"), None);
+        assert_eq!(options.to_string(), "a=1:c=4");
+    }
+}
@@ -0,0 +1,651 @@
+//! Building (and caching) the careful sysroot.
+use std::fs::{self, File, TryLockError};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rustc_build_sysroot::{BuildMode, SysrootBuilder, SysrootConfig, SysrootStatus};
+use rustc_version::VersionMeta;
+
+use crate::util::*;
+use crate::{cargo, patch, rust_src, sanitizer, target, CAREFUL_FLAGS};
+
+/// The name of the file (in the sysroot dir) that records what the sysroot was built with.
+pub const MANIFEST_FILE_NAME: &str = "careful-sysroot.json";
+/// The name of the file (in the sysroot dir) that holds the output of the last sysroot build.
+const BUILD_LOG_FILE_NAME: &str = "build.log";
+
+/// The directory where cargo-careful caches its data.
+pub fn cache_dir() -> PathBuf {
+    let user_dirs = directories::ProjectDirs::from("de", "ralfj", "cargo-careful").unwrap();
+    user_dirs.cache_dir().to_owned()
+}
+
+/// The directory containing all cached sysroots.
+pub fn sysroots_dir() -> PathBuf {
+    cache_dir().join("sysroots")
+}
+
+/// Takes an exclusive lock on the given sysroot dir, so that concurrent cargo-careful processes do
+/// not build (or remove) the same sysroot at the same time. The lock is held until the returned
+/// file is dropped.
+pub fn lock_sysroot(dir: &Path) -> Result<File> {
+    // We lock a file next to the sysroot dir, since the dir itself might get replaced.
+    fs::create_dir_all(dir.parent().unwrap()).context("failed to create sysroot cache dir")?;
+    let lock_file = dir.with_extension("lock");
+    loop {
+        let file = File::options()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&lock_file)
+            .with_context(|| format!("failed to open lock file `{}`", lock_file.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                eprintln!("Waiting for another cargo-careful to finish building the sysroot...");
+                file.lock().context("failed to lock sysroot")?;
+            }
+            Err(TryLockError::Error(e)) => return Err(e).context("failed to lock sysroot"),
+        }
+        // The lock file might have been removed (see `remove_lock_file`) while we were waiting;
+        // then we hold a lock that nobody else sees, and have to start over.
+        if is_same_file(&file, &lock_file) {
+            return Ok(file);
+        }
+    }
+}
+
+/// Whether `file` is the file that is currently at `path`.
+fn is_same_file(file: &File, path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        match (file.metadata(), fs::metadata(path)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+    // Elsewhere, files that are open cannot be removed.
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        path.exists()
+    }
+}
+
+/// Removes the lock file of the given sysroot dir, given the lock returned by `lock_sysroot`.
+/// This is only sound once the sysroot dir is gone, since others may then create a new lock file.
+pub fn remove_lock_file(dir: &Path, lock: File) -> Result<()> {
+    let lock_file = dir.with_extension("lock");
+    fs::remove_file(&lock_file)
+        .with_context(|| format!("failed to remove lock file `{}`", lock_file.display()))?;
+    // Only release the lock once the file is gone, so that anyone waiting for it notices.
+    drop(lock);
+    Ok(())
+}
+
+/// Identifies a rustc version: its commit hash, or its version string if the hash is unknown.
+pub fn rustc_id(rustc_version: &VersionMeta) -> String {
+    rustc_version
+        .commit_hash
+        .clone()
+        .unwrap_or_else(|| rustc_version.short_version_string.clone())
+}
+
+/// The choices that determine how a careful sysroot is built, besides the toolchain, the target
+/// and the user's rustflags.
+#[derive(Clone, Debug, Default)]
+pub struct SysrootOptions {
+    /// Flags that only apply to the sysroot build.
+    pub std_rustflags: Vec<String>,
+    /// The features of the `std` crate, or `None` for a no-std sysroot.
+    pub std_features: Option<Vec<String>>,
+    pub sanitizer: Option<String>,
+    /// Whether to build with `panic=abort`.
+    pub panic_abort: bool,
+    /// Whether `std` should also be usable as a dynamic library (for `-Cprefer-dynamic`).
+    pub std_dylib: bool,
+    /// Patch files and overlay directories to apply to the standard library sources.
+    pub std_patches: Vec<PathBuf>,
+}
+
+/// Everything that determines the contents of a careful sysroot. Each distinct `SysrootId` gets
+/// its own cache directory, so that different variants can all stay cached side by side.
+#[derive(Clone, Debug)]
+pub struct SysrootId {
+    /// The commit hash of the rustc building the sysroot (or its version, if the hash is unknown).
+    pub rustc_commit: String,
+    /// The target, as passed to cargo.
+    pub target: String,
+    /// Identifies the target; for custom targets this includes a hash of the target spec.
+    pub target_key: String,
+    /// All the flags the sysroot gets built with.
+    pub rustflags: Vec<String>,
+    /// The features of the `std` crate, or `None` for a no-std sysroot.
+    pub std_features: Option<Vec<String>>,
+    pub sanitizer: Option<String>,
+    /// Whether the sysroot is built with `panic=abort` (which is also reflected in `rustflags`).
+    pub panic_abort: bool,
+    /// Whether the sysroot contains a `std` dylib (which is also reflected in `rustflags`).
+    pub std_dylib: bool,
+    /// The patches applied to the standard library sources. Only their contents matter for the
+    /// sysroot, which are captured by `std_patch_hash`.
+    pub std_patches: Vec<PathBuf>,
+    pub std_patch_hash: Option<String>,
+}
+
+impl SysrootId {
+    pub fn new(
+        rustc_version: &VersionMeta,
+        target: &str,
+        user_rustflags: &[String],
+        options: &SysrootOptions,
+    ) -> Result<Self> {
+        // User-provided flags must come after CAREFUL_FLAGS so that they can be overridden. The
+        // flags that only apply to the sysroot come last, since they were asked for specifically.
+        let mut rustflags: Vec<String> = CAREFUL_FLAGS.iter().copied().map(Into::into).collect();
+        rustflags.extend_from_slice(user_rustflags);
+        rustflags.extend_from_slice(&options.std_rustflags);
+        if let Some(san) = &options.sanitizer {
+            rustflags.extend(sanitizer::rustflags(san));
+        }
+        let mut std_features = options.std_features.clone();
+        if options.panic_abort {
+            // Without `panic-unwind`, `std` only uses the `panic_abort` runtime.
+            rustflags.push("-Cpanic=abort".into());
+            if let Some(std_features) = &mut std_features {
+                std_features.retain(|f| f != "panic-unwind");
+            }
+        }
+        let std_dylib = options.std_dylib && std_features.is_some();
+        if std_dylib {
+            // `std` is always built as a dylib as well. With this flag, the other dylibs in the
+            // sysroot (like `test`) link to it rather than containing their own copy of `std`.
+            rustflags.push("-Cprefer-dynamic".into());
+        }
+        Ok(SysrootId {
+            rustc_commit: rustc_id(rustc_version),
+            target: target.to_owned(),
+            target_key: target::cache_key(target)?,
+            rustflags,
+            std_features,
+            sanitizer: options.sanitizer.clone(),
+            panic_abort: options.panic_abort,
+            std_dylib,
+            std_patches: options.std_patches.clone(),
+            std_patch_hash: if options.std_patches.is_empty() {
+                None
+            } else {
+                Some(patch::patch_hash(&options.std_patches)?)
+            },
+        })
+    }
+
+    /// Reconstructs the id of a sysroot from its manifest.
+    pub fn from_manifest(manifest: &serde_json::Value) -> Result<Self> {
+        let string = |key: &str| {
+            manifest
+                .get(key)
+                .and_then(serde_json::Value::as_str)
+                .map(str::to_owned)
+                .with_context(|| format!("sysroot manifest lacks `{key}`"))
+        };
+        let list = |key: &str| -> Result<Option<Vec<String>>> {
+            match manifest.get(key) {
+                None | Some(serde_json::Value::Null) => Ok(None),
+                Some(value) => serde_json::from_value(value.clone())
+                    .map(Some)
+                    .with_context(|| format!("sysroot manifest has invalid `{key}`")),
+            }
+        };
+        Ok(SysrootId {
+            rustc_commit: string("rustc-commit")?,
+            target: string("target")?,
+            target_key: string("target-key")?,
+            rustflags: list("rustflags")?.unwrap_or_default(),
+            std_features: list("std-features")?,
+            sanitizer: string("sanitizer").ok(),
+            panic_abort: string("panic").is_ok_and(|p| p == "abort"),
+            std_dylib: manifest.get("std-dylib") == Some(&serde_json::Value::Bool(true)),
+            std_patches: list("std-patches")?
+                .unwrap_or_default()
+                .into_iter()
+                .map(PathBuf::from)
+                .collect(),
+            std_patch_hash: string("std-patch-hash").ok(),
+        })
+    }
+
+    /// The directory this sysroot is cached in.
+    pub fn dir(&self) -> PathBuf {
+        // We do not hash `target` as that might be a machine-specific path; `target_key`
+        // identifies the target just as well.
+        let mut hasher = StableHasher::new();
+        hasher.str(&self.rustc_commit);
+        hasher.str(&self.target_key);
+        hasher.strs(&self.rustflags);
+        hasher.option(self.std_features.as_deref(), StableHasher::strs);
+        hasher.option(self.sanitizer.as_deref(), StableHasher::str);
+        // Only hashed when set, so that unpatched sysroots do not depend on the patch machinery.
+        if let Some(std_patch_hash) = &self.std_patch_hash {
+            hasher.str(std_patch_hash);
+        }
+        sysroots_dir().join(hasher.finish())
+    }
+
+    /// A description of this sysroot, stored next to it so that one can tell what it is. This is
+    /// rewritten each time the sysroot is used, so it also records when that last happened.
+    /// `imported` records whether the sysroot came from `cargo careful sysroot import`, `crates` how
+    /// many crates its build compiled (which lets us estimate the progress of similar builds).
+    pub fn manifest(
+        &self,
+        rustc_version: &VersionMeta,
+        imported: bool,
+        crates: Option<u64>,
+    ) -> serde_json::Value {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        serde_json::json!({
+            "rustc": rustc_version.short_version_string,
+            "rustc-commit": self.rustc_commit,
+            "target": self.target,
+            "target-key": self.target_key,
+            "rustflags": self.rustflags,
+            "std-features": self.std_features,
+            "sanitizer": self.sanitizer,
+            "panic": if self.panic_abort { "abort" } else { "unwind" },
+            "std-dylib": self.std_dylib,
+            "std-patches": self.std_patches,
+            "std-patch-hash": self.std_patch_hash,
+            "last-used": now,
+            "imported": imported,
+            "crates": crates,
+        })
+    }
+}
+
+/// A compact progress indicator for the sysroot build, drawn after the "Preparing a careful
+/// sysroot..." message when the build output is not shown.
+struct Progress {
+    prefix: String,
+    /// The number of crates we expect to be built, if we have an estimate.
+    total: Option<usize>,
+}
+
+impl Progress {
+    fn show(&self, compiled: usize) {
+        let msg = match self.total {
+            Some(total) => format!("[{}/{total} crates]", compiled.min(total)),
+            None => format!("[crates: {compiled}]"),
+        };
+        eprint!("\r{}{msg}", self.prefix);
+    }
+}
+
+/// Copies the output of the sysroot build to the log file, and to stderr if `show_output` is set.
+/// The log file is only created once there is output, so that a previous log is kept if the
+/// sysroot turns out to be fresh. Returns whether anything was logged, and how many crates were
+/// compiled.
+fn log_build_output(
+    mut reader: io::PipeReader,
+    log_path: &Path,
+    show_output: bool,
+    progress: Option<Progress>,
+) -> (bool, usize) {
+    let mut log = None;
+    let mut buf = [0; 4096];
+    // The current incomplete line, and the number of `Compiling` lines so far.
+    let mut line = Vec::new();
+    let mut compiled = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => show_error!("failed to read sysroot build output: {e}"),
+        };
+        if show_output {
+            let _ = io::stderr().write_all(&buf[..n]);
+        }
+        let log = log.get_or_insert_with(|| {
+            fs::create_dir_all(log_path.parent().unwrap())
+                .and_then(|()| File::create(log_path))
+                .unwrap_or_else(|e| show_error!("failed to create `{}`: {e}", log_path.display()))
+        });
+        log.write_all(&buf[..n])
+            .unwrap_or_else(|e| show_error!("failed to write `{}`: {e}", log_path.display()));
+        for &b in &buf[..n] {
+            if b != b'\n' {
+                line.push(b);
+                continue;
+            }
+            if line.trim_ascii_start().starts_with(b"Compiling ") {
+                compiled += 1;
+                if let Some(progress) = &progress {
+                    progress.show(compiled);
+                }
+            }
+            line.clear();
+        }
+    }
+    (log.is_some(), compiled)
+}
+
+/// Formats the duration of a sysroot build.
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 60 {
+        format!("{:.1}s", d.as_secs_f64())
+    } else {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Extracts the last few errors from the build log.
+fn last_errors(log: &str) -> Vec<&str> {
+    const MAX_LINES: usize = 40;
+    // An error starts with a line `error...` and extends until the next empty line.
+    let mut blocks: Vec<Vec<&str>> = Vec::new();
+    let mut in_error = false;
+    for line in log.lines() {
+        if line.starts_with("error") {
+            blocks.push(vec![line]);
+            in_error = true;
+        } else if line.trim().is_empty() {
+            in_error = false;
+        } else if in_error {
+            blocks.last_mut().unwrap().push(line);
+        }
+    }
+    let mut lines = Vec::new();
+    for block in blocks.iter().rev() {
+        if !lines.is_empty() && lines.len() + block.len() > MAX_LINES {
+            break;
+        }
+        lines.splice(0..0, block.iter().copied().chain([""]));
+    }
+    lines
+}
+
+/// Tries to figure out why the sysroot build failed, for some common causes.
+fn diagnose_build_failure(log: &str) -> Option<&'static str> {
+    if log.contains("unknown unstable option") || log.contains("unknown `-Z` flag") {
+        Some(
+            "the toolchain does not support some flags that cargo-careful needs; \
+             cargo-careful requires a recent nightly toolchain",
+        )
+    } else if log.contains("`std` does not have that feature") {
+        Some(
+            "`std` does not have some of the requested features; \
+             check `-Zcareful-std-features` and `careful.std-features`",
+        )
+    } else if log.contains("unknown feature")
+        || log.contains("feature has been removed")
+        || log.contains("E0635")
+    {
+        Some(
+            "the standard library sources do not match the compiler; \
+             make sure `rust-src` (or `RUST_LIB_SRC`) belongs to the same toolchain",
+        )
+    } else if log.contains("could not find specification for target")
+        || log.contains("error loading target specification")
+    {
+        Some("the target is not supported by this toolchain")
+    } else if log.contains("linker") && log.contains("not found") {
+        Some("the linker for this target is not installed")
+    } else {
+        None
+    }
+}
+
+/// Reports a failed sysroot build, and exits.
+fn report_build_failure(
+    err: &anyhow::Error,
+    log_path: &Path,
+    logged: bool,
+    show_output: bool,
+) -> ! {
+    eprintln!();
+    if !logged {
+        // The build did not even get to run cargo.
+        show_error!("failed to build sysroot: {err:#}");
+    }
+    let log = fs::read_to_string(log_path).unwrap_or_default();
+    if !show_output {
+        // The user has not seen any of the output yet.
+        let errors = last_errors(&log);
+        if !errors.is_empty() {
+            eprintln!("The sysroot build reported these errors:\n");
+            for line in errors {
+                eprintln!("{line}");
+            }
+        }
+    }
+    eprintln!(
+        "The full log of the sysroot build is available at `{}`.",
+        log_path.display()
+    );
+    if let Some(diagnosis) = diagnose_build_failure(&log) {
+        eprintln!("hint: {diagnosis}");
+    }
+    show_error!("failed to build sysroot")
+}
+
+/// Reads the manifest of the sysroot in the given dir, if there is a valid one.
+fn read_manifest(dir: &Path) -> Option<serde_json::Value> {
+    let manifest = fs::read(dir.join(MANIFEST_FILE_NAME)).ok()?;
+    serde_json::from_slice(&manifest).ok()
+}
+
+/// Returns the manifests of the other cached sysroots for the same toolchain and target as `id`,
+/// i.e., those that only differ in their flags.
+fn sibling_manifests(id: &SysrootId) -> Vec<serde_json::Value> {
+    let own_dir = id.dir();
+    let Ok(entries) = fs::read_dir(sysroots_dir()) else {
+        return vec![];
+    };
+    entries
+        .filter_map(|entry| {
+            let dir = entry.ok()?.path();
+            (dir != own_dir).then(|| read_manifest(&dir)).flatten()
+        })
+        .filter(|m| {
+            m.get("rustc-commit").and_then(|v| v.as_str()) == Some(id.rustc_commit.as_str())
+                && m.get("target-key").and_then(|v| v.as_str()) == Some(id.target_key.as_str())
+        })
+        .collect()
+}
+
+/// The directory in the sysroot that contains the libraries for the given target.
+pub fn lib_dir(sysroot: &Path, target: &str) -> PathBuf {
+    sysroot
+        .join("lib/rustlib")
+        .join(target::short_name(target))
+        .join("lib")
+}
+
+/// Makes sure that the sysroot has everything that `id` promises, so that we can give a clear
+/// error here rather than having the build fail later.
+fn check_sysroot(id: &SysrootId, sysroot_dir: &Path) {
+    if id.std_dylib {
+        // The dylib is named like `libstd-<hash>.so`, with the prefix and suffix of the target.
+        let has_dylib = fs::read_dir(lib_dir(sysroot_dir, &id.target))
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .any(|name| {
+                name.trim_start_matches("lib").starts_with("std-")
+                    && [".so", ".dylib", ".dll"]
+                        .iter()
+                        .any(|ext| name.ends_with(ext))
+            });
+        if !has_dylib {
+            show_error!(
+                "the careful sysroot in `{}` does not contain a dynamic `std` library",
+                sysroot_dir.display()
+            );
+        }
+    }
+}
+
+pub fn build_sysroot(
+    auto: bool,
+    check_src_version: bool,
+    id: &SysrootId,
+    rustc_version: &VersionMeta,
+    verbose: usize,
+) -> PathBuf {
+    let target = id.target.as_str();
+    let sysroot_dir = id.dir();
+    // Hold the lock while checking and building the sysroot. `build_from_source` checks whether
+    // the sysroot is fresh, so if someone else just built it, we will not do so again.
+    let _lock = lock_sysroot(&sysroot_dir).unwrap_or_else(|e| show_error!("{e:#}"));
+    let previous = read_manifest(&sysroot_dir);
+    let previous_crates = previous.as_ref().and_then(|m| m.get("crates")?.as_u64());
+    let write_manifest = |imported, crates| {
+        fs::write(
+            sysroot_dir.join(MANIFEST_FILE_NAME),
+            id.manifest(rustc_version, imported, crates).to_string(),
+        )
+        .context("failed to write sysroot manifest")
+        .unwrap_or_else(|e| show_error!("{e:#}"));
+    };
+
+    // Imported sysroots are used as-is. The sysroot dir already encodes that it is for the right
+    // toolchain, and we might not even have the sources to check anything else.
+    let imported = previous
+        .as_ref()
+        .is_some_and(|m| m.get("imported") == Some(&serde_json::Value::Bool(true)));
+    if imported {
+        eprintln!("Using imported careful sysroot (target: {target}).");
+        write_manifest(true, previous_crates);
+        check_sysroot(id, &sysroot_dir);
+        return sysroot_dir;
+    }
+
+    let rust_src = rust_src::locate(auto, check_src_version, rustc_version);
+    // Patches are applied to a private copy of the sources, which is kept with the sysroot.
+    let rust_src = match &id.std_patch_hash {
+        None => rust_src,
+        Some(hash) => {
+            patch::patched_src(&rust_src, &id.std_patches, hash, &sysroot_dir.join("src"))
+                .unwrap_or_else(|e| show_error!("{e:#}"))
+        }
+    };
+
+    let mut what = format!("target: {target}");
+    if let Some(san) = &id.sanitizer {
+        what.push_str(&format!(", sanitizer: {san}"));
+    }
+    if id.panic_abort {
+        what.push_str(", panic=abort");
+    }
+    if id.std_dylib {
+        what.push_str(", dynamic std");
+    }
+    if id.std_patch_hash.is_some() {
+        what.push_str(", patched std");
+    }
+    let prefix = format!("Preparing a careful sysroot ({what})... ");
+    eprint!("{prefix}");
+    // By default, the output gets captured. But sometimes we want to show it to the user.
+    let show_output = verbose > 0 || !auto;
+    if show_output {
+        eprintln!();
+    }
+    // If we already built this sysroot before, the builder left its stamp behind. If we built
+    // it with other flags, then that is why we need a new one.
+    let built_before = lib_dir(&sysroot_dir, target).exists();
+    let siblings = sibling_manifests(id);
+    // Estimate the number of crates to build from earlier builds, preferring ones with the same
+    // `std` features since those pull in the same dependencies.
+    let std_features = serde_json::json!(id.std_features);
+    let progress = (!show_output && io::stderr().is_terminal()).then(|| Progress {
+        prefix: prefix.clone(),
+        total: previous_crates
+            .or_else(|| {
+                siblings
+                    .iter()
+                    .filter(|m| m.get("std-features") == Some(&std_features))
+                    .find_map(|m| m.get("crates")?.as_u64())
+            })
+            .map(|n| n as usize),
+    });
+    let showing_progress = progress.is_some();
+    let start = Instant::now();
+    // All output of the build goes through a pipe, so that we can save it in a log file (and show
+    // it to the user if requested).
+    let log_path = sysroot_dir.join(BUILD_LOG_FILE_NAME);
+    let (log_reader, log_writer) =
+        io::pipe().unwrap_or_else(|e| show_error!("failed to create pipe: {e}"));
+    let logger = {
+        let log_path = log_path.clone();
+        thread::spawn(move || log_build_output(log_reader, &log_path, show_output, progress))
+    };
+    let builder = SysrootBuilder::new(&sysroot_dir, target)
+        .build_mode(BuildMode::Build)
+        .rustc_version(rustc_version.clone())
+        .cargo({
+            let mut cmd = cargo();
+            cmd.args(target::cargo_flags(target));
+            cmd.stdout(
+                log_writer
+                    .try_clone()
+                    .unwrap_or_else(|e| show_error!("failed to clone pipe: {e}")),
+            );
+            cmd.stderr(log_writer);
+            cmd
+        })
+        .sysroot_config(match &id.std_features {
+            None => SysrootConfig::NoStd,
+            Some(std_features) => SysrootConfig::WithStd {
+                std_features: std_features.clone(),
+            },
+        })
+        .rustflags(&id.rustflags);
+
+    // The builder owns the only remaining ends of the pipe, so once it is done, the logger will
+    // see the end of the output.
+    let result = builder.build_from_source(&rust_src);
+    let (logged, crates) = logger.join().unwrap();
+    let status = match result {
+        Ok(status) => status,
+        Err(err) => report_build_failure(&err, &log_path, logged, show_output),
+    };
+    let elapsed = format_duration(start.elapsed());
+    let summary = match status {
+        SysrootStatus::AlreadyCached => {
+            write_manifest(false, previous_crates);
+            format!("cached, {elapsed}")
+        }
+        SysrootStatus::SysrootBuilt => {
+            write_manifest(false, Some(crates as u64));
+            if built_before {
+                format!("rebuilt because the sources changed, {elapsed}")
+            } else if !siblings.is_empty() {
+                format!("built because the flags changed, {elapsed}")
+            } else {
+                format!("first build, {elapsed}")
+            }
+        }
+    };
+
+    if showing_progress && crates > 0 {
+        // Overwrite the progress indicator.
+        eprintln!("\r{prefix}done ({summary})\x1b[K");
+    } else if !show_output {
+        eprintln!("done ({summary})");
+    } else {
+        eprintln!(
+            "A sysroot is now available in `{}` ({summary}).",
+            sysroot_dir.display()
+        );
+    }
+
+    check_sysroot(id, &sysroot_dir);
+    sysroot_dir
+}
@@ -0,0 +1,166 @@
+//! Information about targets, which can be given either as a built-in target name or as a path to
+//! a JSON target specification.
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::rustc;
+use crate::util::*;
+
+/// Returns whether the target is a path to a JSON target specification. We use the same logic as
+/// rustc to tell which is which.
+pub fn is_spec_file(target: &str) -> bool {
+    Path::new(target).extension().and_then(OsStr::to_str) == Some("json")
+}
+
+/// Returns the short name of the target: the target name itself for built-in targets, and the file
+/// stem for JSON target specifications. This is what cargo uses in `target.<name>` config tables.
+pub fn short_name(target: &str) -> &str {
+    if is_spec_file(target) {
+        Path::new(target).file_stem().unwrap().to_str().unwrap()
+    } else {
+        target
+    }
+}
+
+/// Turns a target given by the user into the form we pass on to cargo and rustc. Paths to target
+/// specifications are made absolute, relative to `base` (or the current directory if that is
+/// `None`), so that they keep working no matter where the tools are invoked.
+pub fn resolve(target: String, base: Option<&Path>) -> Result<String> {
+    if !is_spec_file(&target) {
+        return Ok(target);
+    }
+    let path = match base {
+        Some(base) => base.join(&target),
+        None => env::current_dir()?.join(&target),
+    };
+    let path = path
+        .canonicalize()
+        .with_context(|| format!("target specification `{target}` not found"))?;
+    path.into_os_string()
+        .into_string()
+        .map_err(|_| anyhow::anyhow!("target specification path `{target}` is not valid UTF-8"))
+}
+
+/// Returns a key identifying the target for caching purposes. For JSON target specifications, this
+/// includes a hash of the file contents, so that editing the file leads to a separate sysroot.
+pub fn cache_key(target: &str) -> Result<String> {
+    if !is_spec_file(target) {
+        return Ok(target.to_owned());
+    }
+    let contents = fs::read(target)
+        .with_context(|| format!("failed to read target specification `{target}`"))?;
+    let mut hasher = StableHasher::new();
+    hasher.bytes(&contents);
+    Ok(format!("{}-{}", short_name(target), hasher.finish()))
+}
+
+/// Extra flags that cargo needs to be able to work with this target.
+pub fn cargo_flags(target: &str) -> &'static [&'static str] {
+    if is_spec_file(target) {
+        &["-Zjson-target-spec"]
+    } else {
+        &[]
+    }
+}
+
+/// Returns the target specification of the given target, as printed by
+/// `rustc --print target-spec-json`.
+pub fn spec(target: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let mut cmd = rustc();
+    cmd.args([
+        "-Z",
+        "unstable-options",
+        "--print",
+        "target-spec-json",
+        "--target",
+        target,
+    ]);
+    let output = cmd
+        .output()
+        .context("rustc --print target-spec-json` failed to run")?;
+
+    let output_str = String::from_utf8(output.stdout)
+        .context("`rustc --print target-spec-json` returned invalid UTF-8")?;
+
+    let json: serde_json::Value = serde_json::from_str(&output_str)
+        .context("`rustc --print target-spec-json` output is invalid JSON")?;
+
+    match json {
+        serde_json::Value::Object(map) => Ok(map),
+        _ => bail!("Target spec JSON has unexpected structure"),
+    }
+}
+
+/// Returns the sanitizers that are supported on this target.
+///
+/// # Errors
+/// Returns `Err` if there was an error when getting the list of supported sanitizers.
+pub fn supported_sanitizers(target: &str) -> Result<Vec<String>> {
+    // To get the list of supported sanitizers, we call `rustc --print target-spec-json`
+    // and parse the output.
+    let map = spec(target)?;
+
+    // The list of supported sanitizers is stored as an array
+    // in the "supported-sanitizers" key of the target JSON
+    match map.get("supported-sanitizers") {
+        Some(serde_json::Value::Array(arr)) => arr
+            .iter()
+            .map(|v| match v {
+                serde_json::Value::String(s) => Ok(s.clone()),
+                _ => bail!("Contents of \"supported-sanitizers\" key in target spec JSON are of unexpected type"),
+            })
+            .collect(),
+        Some(_) => {
+            bail!("Contents of \"supported-sanitizers\" key in target spec JSON are of unexpected type")
+        }
+        None => Ok(vec![]),
+    }
+}
+
+/// Returns whether the given sanitizer is supported on this target.
+///
+/// # Errors
+/// Returns `Err` if there was an error when getting the list of supported sanitizers.
+pub fn sanitizer_supported(san: &str, target: &str) -> Result<bool> {
+    Ok(supported_sanitizers(target)?.iter().any(|s| s == san))
+}
+
+/// Returns whether the standard library is available on this target.
+///
+/// # Errors
+/// Returns `Err` if there was an error when getting the target spec.
+pub fn has_std(target: &str) -> Result<bool> {
+    let map = spec(target)?;
+
+    // Built-in targets document their std support in the "metadata" key.
+    if let Some(std) = map
+        .get("metadata")
+        .and_then(|m| m.get("std"))
+        .and_then(serde_json::Value::as_bool)
+    {
+        return Ok(std);
+    }
+
+    // Otherwise, fall back to the OS: targets without OS do not have std.
+    // This matches what rust/src/bootstrap/config.rs did for a long time.
+    Ok(!matches!(
+        map.get("os").and_then(serde_json::Value::as_str),
+        None | Some("none") | Some("uefi")
+    ))
+}
+
+/// Returns whether this is a macOS target.
+///
+/// # Errors
+/// Returns `Err` if there was an error when getting the target spec.
+pub fn is_macos(target: &str) -> Result<bool> {
+    if !is_spec_file(target) {
+        return Ok(target.contains("-darwin"));
+    }
+    let map = spec(target)?;
+    Ok(map.get("os").and_then(serde_json::Value::as_str) == Some("macos"))
+}
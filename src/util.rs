@@ -123,3 +123,97 @@ pub fn ask_to_run(mut cmd: Command, ask: bool, text: &str) {
         show_error!("failed to {}", text);
     }
 }
+
+/// Extracts the codegen options (`-C name=value`) from a list of rustc flags, as name-value pairs.
+/// The value is empty if the option has none.
+pub fn codegen_options(flags: &[String]) -> impl Iterator<Item = (&str, &str)> {
+    let mut flags = flags.iter();
+    std::iter::from_fn(move || loop {
+        let option = match flags.next()?.strip_prefix("-C") {
+            Some("") => flags.next()?.as_str(),
+            Some(option) => option,
+            None => continue,
+        };
+        return Some(option.split_once('=').unwrap_or((option, "")));
+    })
+}
+
+/// Whether the value of a boolean codegen option turns it on; no value at all also does.
+pub fn codegen_option_enabled(value: &str) -> bool {
+    !matches!(value, "n" | "no" | "off" | "false")
+}
+
+/// A hasher for identifiers that are stored on disk, like the names of cache directories. Unlike
+/// `DefaultHasher` (and the `Hash` impls), it gives the same results across Rust versions and
+/// platforms: it is 64-bit FNV-1a, and every value is fed in as bytes prefixed by its length.
+pub struct StableHasher(u64);
+
+impl StableHasher {
+    pub fn new() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.write(&u64::try_from(bytes.len()).unwrap().to_le_bytes());
+        self.write(bytes);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub fn strs(&mut self, strs: &[String]) {
+        self.write(&u64::try_from(strs.len()).unwrap().to_le_bytes());
+        for s in strs {
+            self.str(s);
+        }
+    }
+
+    /// Hashes whether `value` is set, and then its contents (using `f`).
+    pub fn option<T: ?Sized>(&mut self, value: Option<&T>, f: impl FnOnce(&mut Self, &T)) {
+        self.write(&[u8::from(value.is_some())]);
+        if let Some(value) = value {
+            f(self, value);
+        }
+    }
+
+    /// The hash, formatted for use in file names.
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hasher_is_fnv1a() {
+        let mut hasher = StableHasher::new();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), "af63dc4c8601ec8c");
+    }
+
+    #[test]
+    fn stable_hasher_separates_values() {
+        let hash = |strs: &[&str]| {
+            let mut hasher = StableHasher::new();
+            for s in strs {
+                hasher.str(s);
+            }
+            hasher.finish()
+        };
+        assert_ne!(hash(&["ab", "c"]), hash(&["a", "bc"]));
+        let mut none = StableHasher::new();
+        none.option(None::<&str>, StableHasher::str);
+        let mut empty = StableHasher::new();
+        empty.option(Some(""), StableHasher::str);
+        assert_ne!(none.finish(), empty.finish());
+    }
+}
@@ -8,22 +8,67 @@ cargo install --locked --path ..
 
 # test
 cargo careful setup -v
+cargo careful sysroot list
 cargo careful build --locked -v
 cargo clean
+# a sysroot survives an export/import round trip and is used without being rebuilt
+archive="$(mktemp -d)/careful-sysroot.tar.gz"
+cargo careful sysroot export "$archive"
+cargo careful sysroot clean --all
+cargo careful sysroot import "$archive"
+output=$(cargo careful build --locked 2>&1)
+echo "$output"
+echo "$output" | grep -q "Using imported careful sysroot"
+if echo "$output" | grep -q "Preparing a careful sysroot"; then
+    echo "imported sysroot was rebuilt"
+    exit 1
+fi
+rm -r "$(dirname "$archive")"
+cargo clean
 cargo careful run --locked
 cargo careful test --locked
+# panic=abort, also for tests; the program then aborts at the panic it expects to catch
+cargo careful build -Zcareful-panic=abort --locked
+if cargo careful run -Zcareful-panic=abort --locked; then
+    echo "panic=abort program did not abort"
+    exit 1
+fi
+cargo careful test -Zcareful-panic=abort -Zpanic-abort-tests --locked
+# LTO needs a sysroot with bitcode
+cargo careful run --release --config profile.release.lto=true --locked
+# dynamically linked std
+RUSTFLAGS="$RUSTFLAGS -Cprefer-dynamic" cargo careful run --locked
 
 # test no-std
 pushd test-no_std
 cargo careful setup --target x86_64-unknown-none
 cargo careful build --target x86_64-unknown-none --locked
 cargo clean
+# the target can also come from the cargo configuration
+CARGO_BUILD_TARGET=x86_64-unknown-none cargo careful build --locked
+cargo clean
+# multiple targets in one invocation
+cargo careful build --target x86_64-unknown-none --target aarch64-unknown-none --locked
+cargo clean
+# custom target specification
+rustc -Zunstable-options --print target-spec-json --target x86_64-unknown-none > custom-target.json
+cargo careful build --target custom-target.json --locked
+rm custom-target.json
+cargo clean
 popd
 
 # test with sanitizer -- this only works on Linux; macOS and Windows fail with a linker error
 if uname -s | grep -q "Linux"; then
     cargo careful run -Zcareful-sanitizer --locked
     cargo careful test -Zcareful-sanitizer --locked
+    cargo careful test -Zcareful-sanitizer=thread --locked
+    cargo careful run -Zcareful-sanitizer=memory --locked
+    cargo careful test -Zcareful-leaks --locked
+    # several sanitizers in sequence
+    cargo careful sanitizers
+    cargo careful test -Zcareful-sanitizer=address,thread --locked
+    # the user's runtime options are merged with ours
+    ASAN_OPTIONS=symbolize=1 cargo careful run -Zcareful-sanitizer -v --locked
 fi
 
 # test Apple's Main Thread Checker